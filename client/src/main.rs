@@ -1,26 +1,99 @@
+use chrono::{DateTime, Local, Utc};
 use crossterm::{
     event::{self, KeyCode},
     terminal::{disable_raw_mode, enable_raw_mode},
     ExecutableCommand,
 };
-use protocol::{decode, recv_msg, send_msg, ClientToServer, ServerToClient};
+use protocol::transport::{self, Connection, ServerTrust};
+use protocol::{
+    capability, is_room, recv_msg, send_msg, ClientToServer, Codec, ErrorCode, FileOffer, Message,
+    Presence, Reaction, Request, SearchHit, ServerToClient, UserInfo, FILE_CHUNK_SIZE,
+    MAX_FILE_SIZE, PROTOCOL_VERSION, SYSTEM_SENDER,
+};
 use ratatui::{prelude::*, widgets::*};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
 use std::io;
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const HELP_MESSAGE: &str = "Welcome to Chat-rs. These are the available commands:
     '/users': Display available users.
     '/chat <user>': Enter a chat with a target user.
     '/exit': Exit a chat or Chat-rs itself.
-    '/help': Display this help message.";
+    '/help': Display this help message.
+    Use the Up and Down keys to select a message in a chat, older messages load at the top.";
+
+// Only shown if the server supports rooms
+const ROOM_HELP_MESSAGE: &str = "    '/rooms': Display available rooms.
+    '/create #<room>': Create a new room and enter it.
+    '/join #<room>': Join a room and enter it.
+    '/leave [#<room>]': Leave the current or the given room.";
+
+// Only shown if the server supports presence
+const PRESENCE_HELP_MESSAGE: &str =
+    "    '/online', '/away', '/dnd': Set your presence to online, away or do not disturb.
+    '/status [<text>]': Set or clear the status shown next to your presence.";
+
+// Only shown if the server supports editing messages
+const EDIT_HELP_MESSAGE: &str =
+    "    '/edit <text>': Replace the text of the selected or your last message.
+    '/delete': Delete the selected or your last message.";
+
+// Only shown if the server supports reactions
+const REACTION_HELP_MESSAGE: &str =
+    "    '/react <emoji>': React to the selected or the last message, again to take it back.";
+
+// Only shown if the server supports threads
+const THREAD_HELP_MESSAGE: &str =
+    "    '/reply <message>': Reply in the thread of the selected or the last message.
+    '/thread': Show or hide the replies in the thread of the selected or the last message.";
+
+// Only shown if the server supports search
+const SEARCH_HELP_MESSAGE: &str = "    '/search <words>': Search all your chats.
+    '/find <words>': Search the current chat.
+    '/open <n>': Open the chat of search result number n.";
+
+// Only shown if the server supports file transfer
+const FILE_HELP_MESSAGE: &str = "    '/send <path>': Send a file to the current chat.
+    '/accept <n> [<path>]': Save offered file number n, by default under its own name.";
+
+// Only shown if the server supports blocking users
+const BLOCK_HELP_MESSAGE: &str = "    '/block <user>': Stop receiving anything from a user.
+    '/unblock <user>': Receive messages from a blocked user again.
+    '/blocked': Display the users you blocked.";
+
+// Optional features this client knows how to use
+const CAPABILITIES: &[&str] = &[
+    capability::ROOMS,
+    capability::OFFLINE_DELIVERY,
+    capability::READ_RECEIPTS,
+    capability::TYPING,
+    capability::PRESENCE,
+    capability::EDITING,
+    capability::REACTIONS,
+    capability::THREADS,
+    capability::SEARCH,
+    capability::FILES,
+    capability::BLOCKING,
+];
+
+// Typing is reported as stopped this long after the last key press
+const TYPING_TIMEOUT: Duration = Duration::from_secs(3);
+
+const LOGIN_PROMPT: &str =
+    "Please enter your user name, or '/new <user>' to create a new account...";
 
 enum Status {
     Initializing,
-    Registering,
+    EnteringHandle,
+    EnteringPassword,
+    Authenticating,
     InConsole,
     InChat,
     Exit,
@@ -32,15 +105,192 @@ struct ClientState {
     display: Vec<DisplayMessage>,
     title: String,
     handle: Option<String>,
+    // Handle to log in as and whether a new account should be created for it
+    login: Option<(String, bool)>,
     input: String,
+    // Optional features announced by the server in the handshake
+    capabilities: Vec<String>,
+    codec: Codec,
+    // ID for the next request that should be acknowledged
+    next_request_id: u64,
+    // Last message the current partner has seen, only tracked in direct chats
+    partner_read: Option<u64>,
+    // Other users currently typing in the current chat
+    typing: Vec<String>,
+    // Latest known presence of other users
+    users: HashMap<String, UserInfo>,
+    // ID of the message picked with the arrow keys for '/edit', '/delete', '/react' and '/reply'
+    selected: Option<u64>,
+    // Threads whose replies are shown, by the ID of their first message
+    expanded: HashSet<u64>,
+    // The server has older messages of the current chat than the ones shown
+    has_more: bool,
+    // Older messages have been requested and not arrived yet
+    loading_history: bool,
+    // Chat and message ID of every result of the last search, for '/open'
+    search_hits: Vec<(String, u64)>,
+    // Chat and ID of the message to select once it has been loaded
+    jump_to: Option<(String, u64)>,
+    // Files waiting for the server to acknowledge their offer, by request ID
+    uploads: HashMap<u64, Vec<u8>>,
+    // Files offered to the user and the ones being downloaded, by transfer ID
+    offers: HashMap<u64, FileOffer>,
+    downloads: HashMap<u64, Download>,
+}
+
+/// An accepted file whose chunks are arriving.
+struct Download {
+    offer: FileOffer,
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+impl ClientState {
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    fn help_messages(&self) -> Vec<DisplayMessage> {
+        let mut lines: Vec<&str> = HELP_MESSAGE.split("\n").collect();
+        if self.supports(capability::ROOMS) {
+            lines.extend(ROOM_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::PRESENCE) {
+            lines.extend(PRESENCE_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::EDITING) {
+            lines.extend(EDIT_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::REACTIONS) {
+            lines.extend(REACTION_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::THREADS) {
+            lines.extend(THREAD_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::SEARCH) {
+            lines.extend(SEARCH_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::FILES) {
+            lines.extend(FILE_HELP_MESSAGE.split("\n"));
+        }
+        if self.supports(capability::BLOCKING) {
+            lines.extend(BLOCK_HELP_MESSAGE.split("\n"));
+        }
+
+        lines
+            .into_iter()
+            .map(|l| DisplayMessage::system(String::from(l)))
+            .collect()
+    }
+
+    /// The message '/edit' and '/delete' act on: the selected one, otherwise
+    /// the last message sent by this user that the server has stored.
+    fn edit_target(&self) -> Option<u64> {
+        self.selected.or_else(|| {
+            self.display
+                .iter()
+                .rev()
+                .filter(|m| matches!(m.mode, DisplayMessageMode::User))
+                .find_map(|m| m.id)
+        })
+    }
+
+    /// The message '/react', '/reply' and '/thread' act on: the selected one,
+    /// otherwise the bottom most stored message.
+    fn react_target(&self) -> Option<&DisplayMessage> {
+        match self.selected {
+            Some(id) => self.display.iter().find(|m| m.id == Some(id)),
+            None => thread_view(&self.display, &self.expanded)
+                .into_iter()
+                .rev()
+                .map(|(m, _)| m)
+                .find(|m| m.id.is_some()),
+        }
+    }
+
+    /// Move the selection to the previous (`up`) or next stored message. Moving
+    /// down from the newest one clears the selection. Returns whether the
+    /// selection tried to move past the oldest message.
+    fn move_selection(&mut self, up: bool) -> bool {
+        let stored: Vec<u64> = thread_view(&self.display, &self.expanded)
+            .into_iter()
+            .filter_map(|(m, _)| m.id)
+            .collect();
+        let current = self
+            .selected
+            .and_then(|id| stored.iter().position(|&s| s == id));
+
+        self.selected = match (current, up) {
+            (None, true) => stored.last().copied(),
+            (None, false) => None,
+            (Some(index), true) => stored.get(index.saturating_sub(1)).copied(),
+            (Some(index), false) => stored.get(index + 1).copied(),
+        };
+
+        up && (stored.is_empty() || current == Some(0))
+    }
 }
 
 enum Input {
     ListUsers,
-    Chat { target: String },
+    Chat {
+        target: String,
+    },
+    ListRooms,
+    CreateRoom {
+        room: String,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: Option<String>,
+    },
+    SetPresence {
+        presence: Presence,
+    },
+    SetStatus {
+        status: Option<String>,
+    },
+    EditMessage {
+        content: String,
+    },
+    DeleteMessage,
+    React {
+        emoji: String,
+    },
+    Reply {
+        message: String,
+    },
+    ToggleThread,
+    // Search the current chat (`here`) or all of them
+    Search {
+        query: String,
+        here: bool,
+    },
+    Open {
+        hit: usize,
+    },
+    SendFile {
+        path: PathBuf,
+    },
+    AcceptFile {
+        transfer_id: u64,
+        path: Option<PathBuf>,
+    },
+    // Block (`active`) or unblock a user
+    Block {
+        handle: String,
+        active: bool,
+    },
+    ListBlocked,
     Exit,
-    ChatMessage { message: String },
-    InvalidCommand { message: String },
+    ChatMessage {
+        message: String,
+    },
+    InvalidCommand {
+        message: String,
+    },
     Help,
 }
 
@@ -53,9 +303,127 @@ enum DisplayMessageMode {
 
 #[derive(Clone)]
 struct DisplayMessage {
+    // Server assigned ID, unknown for notices and until a sent message is acknowledged
+    id: Option<u64>,
     content: String,
     sender: String,
     mode: DisplayMessageMode,
+    // Seconds since the Unix epoch, not shown for system notices
+    timestamp: Option<u64>,
+    // Only tracked for messages sent by this client
+    delivery: Option<Delivery>,
+    edited: bool,
+    reactions: Vec<Reaction>,
+    // First message of the thread this one replies in
+    parent: Option<u64>,
+}
+
+#[derive(Clone)]
+enum Delivery {
+    // Waiting for the server to acknowledge the request with this ID
+    Pending(u64),
+    Sent,
+    Failed,
+}
+
+impl DisplayMessage {
+    fn system(content: String) -> Self {
+        DisplayMessage {
+            id: None,
+            content,
+            sender: SYSTEM_SENDER.to_string(),
+            mode: DisplayMessageMode::System,
+            timestamp: None,
+            delivery: None,
+            edited: false,
+            reactions: Vec::new(),
+            parent: None,
+        }
+    }
+
+    fn from_message(message: Message, handle: &str) -> Self {
+        DisplayMessage {
+            id: Some(message.id),
+            content: message.content,
+            mode: if message.sender == handle {
+                DisplayMessageMode::User
+            } else if message.sender == SYSTEM_SENDER {
+                DisplayMessageMode::System
+            } else {
+                DisplayMessageMode::OtherUser
+            },
+            sender: message.sender,
+            // History logged before timestamps existed has none
+            timestamp: Some(message.timestamp).filter(|&t| t > 0),
+            delivery: None,
+            edited: message.edited.is_some(),
+            reactions: message.reactions,
+            parent: message.parent,
+        }
+    }
+}
+
+/// Local time of day for messages sent today, otherwise the date as well.
+fn format_timestamp(timestamp: u64) -> String {
+    let Some(time) = DateTime::from_timestamp(timestamp as i64, 0) else {
+        return String::new();
+    };
+
+    let time = time.with_timezone(&Local);
+    if time.date_naive() == Local::now().date_naive() {
+        time.format("%H:%M").to_string()
+    } else {
+        time.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
+
+/// A file size in the largest unit that keeps it above one, e.g. "1.5 MB".
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// Presence and status of a user as shown by '/users' and in the chat title.
+fn describe_presence(user: &UserInfo) -> String {
+    let presence = match user.presence {
+        Presence::Online => "online".to_string(),
+        Presence::Away => "away".to_string(),
+        Presence::DoNotDisturb => "do not disturb".to_string(),
+        Presence::Offline => match user.last_seen {
+            Some(last_seen) => format!("offline, last seen {}", format_timestamp(last_seen)),
+            None => "offline".to_string(),
+        },
+    };
+
+    match &user.status {
+        Some(status) => format!("{} - {}", presence, status),
+        None => presence,
+    }
+}
+
+impl Input {
+    /// The capability the server has to announce for the command to work.
+    fn capability(&self) -> Option<&'static str> {
+        match self {
+            Input::ListRooms
+            | Input::CreateRoom { .. }
+            | Input::JoinRoom { .. }
+            | Input::LeaveRoom { .. } => Some(capability::ROOMS),
+            Input::SetPresence { .. } | Input::SetStatus { .. } => Some(capability::PRESENCE),
+            Input::EditMessage { .. } | Input::DeleteMessage => Some(capability::EDITING),
+            Input::React { .. } => Some(capability::REACTIONS),
+            Input::Reply { .. } | Input::ToggleThread => Some(capability::THREADS),
+            Input::Search { .. } | Input::Open { .. } => Some(capability::SEARCH),
+            Input::SendFile { .. } | Input::AcceptFile { .. } => Some(capability::FILES),
+            Input::Block { .. } | Input::ListBlocked => Some(capability::BLOCKING),
+            _ => None,
+        }
+    }
 }
 
 fn parse_input(input: String) -> Input {
@@ -76,6 +444,133 @@ fn parse_input(input: String) -> Input {
                 message: "No target user requested.".to_string(),
             },
         },
+        Some("/rooms") => Input::ListRooms,
+        Some("/create") => match parts.next() {
+            Some(room) => Input::CreateRoom {
+                room: room.to_string(),
+            },
+            _ => Input::InvalidCommand {
+                message: "No room name given.".to_string(),
+            },
+        },
+        Some("/join") => match parts.next() {
+            Some(room) => Input::JoinRoom {
+                room: room.to_string(),
+            },
+            _ => Input::InvalidCommand {
+                message: "No room name given.".to_string(),
+            },
+        },
+        Some("/leave") => Input::LeaveRoom {
+            room: parts.next().map(|r| r.to_string()),
+        },
+        Some("/online") => Input::SetPresence {
+            presence: Presence::Online,
+        },
+        Some("/away") => Input::SetPresence {
+            presence: Presence::Away,
+        },
+        Some("/dnd") => Input::SetPresence {
+            presence: Presence::DoNotDisturb,
+        },
+        Some("/status") => {
+            // The status is the rest of the line, spaces included
+            let status = input["/status".len()..].trim();
+            Input::SetStatus {
+                status: Some(status.to_string()).filter(|s| !s.is_empty()),
+            }
+        }
+        Some("/edit") => {
+            let content = input["/edit".len()..].trim();
+            if content.is_empty() {
+                Input::InvalidCommand {
+                    message: "No new text given.".to_string(),
+                }
+            } else {
+                Input::EditMessage {
+                    content: content.to_string(),
+                }
+            }
+        }
+        Some("/delete") => Input::DeleteMessage,
+        Some("/react") => match parts.next() {
+            Some(emoji) => Input::React {
+                emoji: emoji.to_string(),
+            },
+            _ => Input::InvalidCommand {
+                message: "No emoji given.".to_string(),
+            },
+        },
+        Some("/reply") => {
+            let message = input["/reply".len()..].trim();
+            if message.is_empty() {
+                Input::InvalidCommand {
+                    message: "No reply given.".to_string(),
+                }
+            } else {
+                Input::Reply {
+                    message: message.to_string(),
+                }
+            }
+        }
+        Some("/thread") => Input::ToggleThread,
+        Some(command @ ("/search" | "/find")) => {
+            let query = input[command.len()..].trim();
+            if query.is_empty() {
+                Input::InvalidCommand {
+                    message: "No search words given.".to_string(),
+                }
+            } else {
+                Input::Search {
+                    query: query.to_string(),
+                    here: command == "/find",
+                }
+            }
+        }
+        Some("/open") => match parts.next().and_then(|n| n.parse().ok()) {
+            Some(hit) => Input::Open { hit },
+            None => Input::InvalidCommand {
+                message: "No search result number given.".to_string(),
+            },
+        },
+        Some("/send") => {
+            // Paths may contain spaces
+            let path = input["/send".len()..].trim();
+            if path.is_empty() {
+                Input::InvalidCommand {
+                    message: "No file given.".to_string(),
+                }
+            } else {
+                Input::SendFile {
+                    path: PathBuf::from(path),
+                }
+            }
+        }
+        Some("/accept") => {
+            let args = input["/accept".len()..].trim();
+            let (transfer_id, path) = args.split_once(' ').unwrap_or((args, ""));
+            match transfer_id.parse() {
+                Ok(transfer_id) => Input::AcceptFile {
+                    transfer_id,
+                    path: Some(path.trim())
+                        .filter(|p| !p.is_empty())
+                        .map(PathBuf::from),
+                },
+                Err(_) => Input::InvalidCommand {
+                    message: "No file number given.".to_string(),
+                },
+            }
+        }
+        Some(command @ ("/block" | "/unblock")) => match parts.next() {
+            Some(handle) => Input::Block {
+                handle: handle.to_string(),
+                active: command == "/block",
+            },
+            _ => Input::InvalidCommand {
+                message: "No user given.".to_string(),
+            },
+        },
+        Some("/blocked") => Input::ListBlocked,
         Some("/exit") => Input::Exit,
         Some("/help") => Input::Help,
         _ => Input::InvalidCommand {
@@ -84,143 +579,345 @@ fn parse_input(input: String) -> Input {
     }
 }
 
-fn process_input(client_state: &Arc<Mutex<ClientState>>, mut stream: &TcpStream) -> io::Result<()> {
+/// Send a request the server doesn't need to acknowledge.
+fn send_request(mut stream: &Connection, codec: Codec, body: ClientToServer) -> io::Result<()> {
+    send_msg(&mut stream, codec, &Request::from(body))
+}
+
+fn process_input(client_state: &Arc<Mutex<ClientState>>, stream: &Connection) -> io::Result<()> {
     let mut state = client_state.lock().unwrap();
 
     let input = state.input.clone();
+    let codec = state.codec;
 
     match state.status {
         Status::Initializing => {}
-        Status::Registering => {
-            // Input is the handle
-            let handle = input.trim();
-
-            let _ = send_msg(
-                &mut stream,
-                &ClientToServer::Register {
-                    handle: handle.to_string(),
-                },
-            );
+        Status::EnteringHandle => {
+            let mut parts = input.split_whitespace();
+            let login = match (parts.next(), parts.next()) {
+                (Some("/new"), Some(handle)) => Some((handle.to_string(), true)),
+                (Some(handle), None) if !handle.starts_with('/') => {
+                    Some((handle.to_string(), false))
+                }
+                _ => None,
+            };
 
-            state.display.push(DisplayMessage {
-                content: format!("Requested handle: {}", handle),
-                sender: "System".to_string(),
-                mode: DisplayMessageMode::System,
-            });
-
-            state.display.extend(
-                String::from(HELP_MESSAGE)
-                    .split("\n")
-                    .map(|l| DisplayMessage {
-                        content: String::from(l),
-                        sender: "System".to_string(),
-                        mode: DisplayMessageMode::System,
-                    }),
-            );
+            match login {
+                Some((handle, create)) => {
+                    state.display.push(DisplayMessage::system(if create {
+                        format!("Creating account '{}'. Choose a password...", handle)
+                    } else {
+                        format!("Logging in as '{}'. Enter your password...", handle)
+                    }));
+                    state.login = Some((handle, create));
+                    state.status = Status::EnteringPassword;
+                }
+                None => {
+                    state
+                        .display
+                        .push(DisplayMessage::system(LOGIN_PROMPT.to_string()));
+                }
+            }
+        }
+        Status::EnteringPassword => {
+            // Input is the password, never echo it back
+            if let Some((handle, create)) = state.login.clone() {
+                let password = input.clone();
+                let msg = if create {
+                    ClientToServer::CreateAccount { handle, password }
+                } else {
+                    ClientToServer::Login { handle, password }
+                };
+                let _ = send_request(stream, codec, msg);
+                state.status = Status::Authenticating;
+            }
         }
         Status::InConsole => {
             // In the main console
             match parse_input(input.trim().to_string()) {
+                command if command.capability().is_some_and(|c| !state.supports(c)) => {
+                    state.display.push(DisplayMessage::system(
+                        "This server doesn't support that command.".to_string(),
+                    ));
+                }
                 Input::ListUsers => {
-                    let _ = send_msg(&mut stream, &ClientToServer::ListUsers);
+                    let _ = send_request(stream, codec, ClientToServer::ListUsers);
                 }
                 Input::Chat { target } => {
-                    let _ = send_msg(&mut stream, &ClientToServer::GetMessages { target });
+                    let _ = send_request(stream, codec, get_messages(target, None));
+                }
+                Input::ListRooms => {
+                    let _ = send_request(stream, codec, ClientToServer::ListRooms);
+                }
+                Input::CreateRoom { room } => {
+                    let _ = send_request(stream, codec, ClientToServer::CreateRoom { room });
+                }
+                Input::JoinRoom { room } => {
+                    let _ = send_request(stream, codec, ClientToServer::JoinRoom { room });
+                }
+                Input::LeaveRoom { room: Some(room) } => {
+                    let _ = send_request(stream, codec, ClientToServer::LeaveRoom { room });
+                }
+                Input::LeaveRoom { room: None } => {
+                    state
+                        .display
+                        .push(DisplayMessage::system("No room name given.".to_string()));
+                }
+                Input::SetPresence { presence } => {
+                    let _ = send_request(stream, codec, ClientToServer::SetPresence { presence });
+                }
+                Input::SetStatus { status } => {
+                    let _ = send_request(stream, codec, ClientToServer::SetStatus { status });
+                }
+                Input::Search { query, here: false } => {
+                    let _ = send_request(
+                        stream,
+                        codec,
+                        ClientToServer::Search {
+                            query,
+                            conversation: None,
+                        },
+                    );
+                }
+                Input::Open { hit } => {
+                    let _ = open_search_hit(stream, &mut state, hit);
+                }
+                Input::AcceptFile { transfer_id, path } => {
+                    let _ = accept_file(stream, &mut state, transfer_id, path);
+                }
+                Input::Block { handle, active } => {
+                    let _ = send_request(stream, codec, ClientToServer::Block { handle, active });
+                }
+                Input::ListBlocked => {
+                    let _ = send_request(stream, codec, ClientToServer::ListBlocked);
                 }
                 Input::Exit => {
                     state.status = Status::Exit;
                 }
                 Input::ChatMessage { message: _message } => {
-                    state.display.push(DisplayMessage {
-                        content: "Please connect to a chat to send a message.".to_string(),
-                        sender: "System".to_string(),
-                        mode: DisplayMessageMode::System,
-                    });
+                    state.display.push(DisplayMessage::system(
+                        "Please connect to a chat to send a message.".to_string(),
+                    ));
+                }
+                Input::EditMessage { .. }
+                | Input::DeleteMessage
+                | Input::React { .. }
+                | Input::Reply { .. }
+                | Input::ToggleThread
+                | Input::Search { here: true, .. }
+                | Input::SendFile { .. } => {
+                    state.display.push(DisplayMessage::system(
+                        "Please connect to a chat first.".to_string(),
+                    ));
                 }
                 Input::InvalidCommand { message } => {
-                    state.display.push(DisplayMessage {
-                        content: message,
-                        sender: "System".to_string(),
-                        mode: DisplayMessageMode::System,
-                    });
+                    state.display.push(DisplayMessage::system(message));
                 }
                 Input::Help => {
-                    state
-                        .display
-                        .extend(
-                            String::from(HELP_MESSAGE)
-                                .split("\n")
-                                .map(|l| DisplayMessage {
-                                    content: String::from(l),
-                                    sender: "System".to_string(),
-                                    mode: DisplayMessageMode::System,
-                                }),
-                        );
+                    let help = state.help_messages();
+                    state.display.extend(help);
                 }
             }
         }
         Status::InChat => {
             // In a chat
             match parse_input(input.trim().to_string()) {
+                command if command.capability().is_some_and(|c| !state.supports(c)) => {
+                    state.display.push(DisplayMessage::system(
+                        "This server doesn't support that command.".to_string(),
+                    ));
+                }
                 Input::ListUsers => {
-                    send_msg(&mut stream, &ClientToServer::ListUsers)?;
+                    send_request(stream, codec, ClientToServer::ListUsers)?;
                 }
                 Input::Chat { target } => {
-                    send_msg(&mut stream, &ClientToServer::GetMessages { target })?;
+                    send_request(stream, codec, get_messages(target, None))?;
+                }
+                Input::ListRooms => {
+                    send_request(stream, codec, ClientToServer::ListRooms)?;
+                }
+                Input::CreateRoom { room } => {
+                    send_request(stream, codec, ClientToServer::CreateRoom { room })?;
+                }
+                Input::JoinRoom { room } => {
+                    send_request(stream, codec, ClientToServer::JoinRoom { room })?;
+                }
+                Input::LeaveRoom { room } => {
+                    // Without an argument leave the room currently being viewed
+                    match room.or(state.current_partner.clone().filter(|p| is_room(p))) {
+                        Some(room) => {
+                            send_request(stream, codec, ClientToServer::LeaveRoom { room })?;
+                        }
+                        None => {
+                            state
+                                .display
+                                .push(DisplayMessage::system("No room name given.".to_string()));
+                        }
+                    }
+                }
+                Input::SetPresence { presence } => {
+                    send_request(stream, codec, ClientToServer::SetPresence { presence })?;
+                }
+                Input::SetStatus { status } => {
+                    send_request(stream, codec, ClientToServer::SetStatus { status })?;
+                }
+                Input::EditMessage { .. } | Input::DeleteMessage
+                    if state.edit_target().is_none() =>
+                {
+                    state.display.push(DisplayMessage::system(
+                        "You haven't sent a message in this chat yet.".to_string(),
+                    ));
+                }
+                Input::EditMessage { content } => {
+                    if let Some(target) = state.current_partner.clone() {
+                        let message_id = state.edit_target().unwrap();
+                        send_request(
+                            stream,
+                            codec,
+                            ClientToServer::EditMessage {
+                                target,
+                                message_id,
+                                content,
+                            },
+                        )?;
+                        state.selected = None;
+                    }
+                }
+                Input::DeleteMessage => {
+                    if let Some(target) = state.current_partner.clone() {
+                        let message_id = state.edit_target().unwrap();
+                        send_request(
+                            stream,
+                            codec,
+                            ClientToServer::DeleteMessage { target, message_id },
+                        )?;
+                        state.selected = None;
+                    }
+                }
+                Input::React { emoji } => {
+                    let handle = state.handle.clone().unwrap_or_default();
+                    let reaction = state.react_target().and_then(|m| {
+                        // Reacting twice with the same emoji takes the reaction back
+                        let reacted = m
+                            .reactions
+                            .iter()
+                            .any(|r| r.emoji == emoji && r.users.contains(&handle));
+                        m.id.map(|id| (id, !reacted))
+                    });
+
+                    match (reaction, state.current_partner.clone()) {
+                        (Some((message_id, active)), Some(target)) => {
+                            send_request(
+                                stream,
+                                codec,
+                                ClientToServer::React {
+                                    target,
+                                    message_id,
+                                    emoji,
+                                    active,
+                                },
+                            )?;
+                            state.selected = None;
+                        }
+                        _ => {
+                            state.display.push(DisplayMessage::system(
+                                "There is no message to react to.".to_string(),
+                            ));
+                        }
+                    }
                 }
                 Input::Exit => {
                     state.status = Status::InConsole;
                     state.display.clear();
                     state.current_partner = None;
+                    state.partner_read = None;
+                    state.typing.clear();
+                    state.selected = None;
+                    state.expanded.clear();
+                    state.has_more = false;
+                    state.loading_history = false;
                     state.title = format!("Console ({}))", state.handle.clone().unwrap());
                 }
+                Input::Reply { message } => {
+                    // Replies to a reply go into the same thread
+                    let parent = state.react_target().and_then(|m| m.parent.or(m.id));
+
+                    match (parent, state.current_partner.clone()) {
+                        (Some(parent), Some(target)) => {
+                            state.expanded.insert(parent);
+                            send_chat_message(stream, &mut state, target, message, Some(parent));
+                            state.selected = None;
+                        }
+                        _ => {
+                            state.display.push(DisplayMessage::system(
+                                "There is no message to reply to.".to_string(),
+                            ));
+                        }
+                    }
+                }
+                Input::Search { query, here } => {
+                    let conversation = state.current_partner.clone().filter(|_| here);
+                    send_request(
+                        stream,
+                        codec,
+                        ClientToServer::Search {
+                            query,
+                            conversation,
+                        },
+                    )?;
+                }
+                Input::Open { hit } => {
+                    open_search_hit(stream, &mut state, hit)?;
+                }
+                Input::SendFile { path } => {
+                    if let Some(target) = state.current_partner.clone() {
+                        send_file(stream, &mut state, target, path)?;
+                    }
+                }
+                Input::AcceptFile { transfer_id, path } => {
+                    accept_file(stream, &mut state, transfer_id, path)?;
+                }
+                Input::Block { handle, active } => {
+                    send_request(stream, codec, ClientToServer::Block { handle, active })?;
+                }
+                Input::ListBlocked => {
+                    send_request(stream, codec, ClientToServer::ListBlocked)?;
+                }
+                Input::ToggleThread => {
+                    match state.react_target().and_then(|m| m.parent.or(m.id)) {
+                        Some(root) => {
+                            if !state.expanded.remove(&root) {
+                                state.expanded.insert(root);
+                            }
+                            // The selected reply may have been hidden
+                            state.selected = None;
+                        }
+                        None => {
+                            state.display.push(DisplayMessage::system(
+                                "There is no thread to show.".to_string(),
+                            ));
+                        }
+                    }
+                }
                 Input::ChatMessage { message } => {
-                    if let Some(current_partner) = &state.current_partner {
-                        let _ = send_msg(
-                            &mut stream,
-                            &ClientToServer::SendMessage {
-                                content: message.clone(),
-                                target: current_partner.to_string(),
-                            },
-                        );
-                        let handle = state.handle.as_ref().unwrap().to_string();
-                        state.display.push(DisplayMessage {
-                            content: message.clone(),
-                            sender: handle,
-                            mode: DisplayMessageMode::User,
-                        });
+                    if let Some(current_partner) = state.current_partner.clone() {
+                        send_chat_message(stream, &mut state, current_partner, message, None);
                     } else {
-                        state.display.push(DisplayMessage {
-                            content: "Please connect to a chat before sending a message."
-                                .to_string(),
-                            sender: "System".to_string(),
-                            mode: DisplayMessageMode::System,
-                        });
+                        state.display.push(DisplayMessage::system(
+                            "Please connect to a chat before sending a message.".to_string(),
+                        ));
                     }
                 }
                 Input::InvalidCommand { message } => {
-                    state.display.push(DisplayMessage {
-                        content: message,
-                        sender: "System".to_string(),
-                        mode: DisplayMessageMode::System,
-                    });
+                    state.display.push(DisplayMessage::system(message));
                 }
                 Input::Help => {
-                    state
-                        .display
-                        .extend(
-                            String::from(HELP_MESSAGE)
-                                .split("\n")
-                                .map(|l| DisplayMessage {
-                                    content: String::from(l),
-                                    sender: "System".to_string(),
-                                    mode: DisplayMessageMode::System,
-                                }),
-                        );
+                    let help = state.help_messages();
+                    state.display.extend(help);
                 }
             }
         }
-        _ => {}
+        Status::Authenticating | Status::Exit => {}
     }
 
     state.input.clear();
@@ -232,15 +929,38 @@ fn draw_terminal(
     terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>,
     client_state: &Arc<Mutex<ClientState>>,
 ) -> io::Result<()> {
-    let (title, display, input) = {
+    let (title, display, input, partner_read, typing, selected, expanded) = {
         let state = client_state.lock().unwrap();
+        let input = match state.status {
+            Status::EnteringPassword => "*".repeat(state.input.chars().count()),
+            _ => state.input.clone(),
+        };
+        // Show the partner's presence next to their handle in direct chats
+        let title = match state
+            .current_partner
+            .as_ref()
+            .and_then(|partner| state.users.get(partner))
+        {
+            Some(user) => format!("{} ({})", state.title, describe_presence(user)),
+            None => state.title.clone(),
+        };
         (
-            state.title.clone(),
+            title,
             state.display.clone(),
-            state.input.clone(),
+            input,
+            state.partner_read,
+            state.typing.clone(),
+            state.selected,
+            state.expanded.clone(),
         )
     };
 
+    let title = match typing.as_slice() {
+        [] => title,
+        [user] => format!("{} - {} is typing…", title, user),
+        users => format!("{} - {} are typing…", title, users.join(", ")),
+    };
+
     let mut list_state = ListState::default();
 
     terminal.draw(|frame| {
@@ -250,13 +970,20 @@ fn draw_terminal(
             .constraints([Constraint::Percentage(90), Constraint::Percentage(10)])
             .split(area);
 
-        if !display.is_empty() {
-            list_state.select(Some(display.len().saturating_sub(1)));
+        let view = thread_view(&display, &expanded);
+
+        // Keep the selected message in view, otherwise follow the newest one
+        let selected_index =
+            selected.and_then(|id| view.iter().position(|(m, _)| m.id == Some(id)));
+        if selected_index.is_some() {
+            list_state.select(selected_index);
+        } else if !view.is_empty() {
+            list_state.select(Some(view.len().saturating_sub(1)));
         } else {
             list_state.select(None);
         }
 
-        let msg_list = List::new(display.iter().map(|m| {
+        let msg_list = List::new(view.iter().map(|&(m, reply)| {
             let sender = format!("[{}] ", m.sender.to_uppercase());
 
             let sender_formatted = match m.mode {
@@ -265,9 +992,66 @@ fn draw_terminal(
                 DisplayMessageMode::System => sender.red().bold(),
             };
 
-            Line::from(vec![sender_formatted, m.content.as_str().into()])
+            let mut spans = Vec::new();
+            if reply {
+                spans.push("    ↳ ".dark_gray());
+            }
+            if let Some(timestamp) = m.timestamp {
+                spans.push(format!("{} ", format_timestamp(timestamp)).dark_gray());
+            }
+            spans.extend([sender_formatted, m.content.as_str().into()]);
+            if m.edited {
+                spans.push(" (edited)".dark_gray());
+            }
+            let seen = matches!(m.mode, DisplayMessageMode::User)
+                && m.id.zip(partner_read).is_some_and(|(id, read)| id <= read);
+            match m.delivery {
+                _ if seen => spans.push(" ✓✓".green()),
+                Some(Delivery::Pending(_)) => spans.push(" …".dark_gray()),
+                Some(Delivery::Sent) => spans.push(" ✓".dark_gray()),
+                Some(Delivery::Failed) => spans.push(" ✗ not delivered".red()),
+                None => {}
+            }
+
+            // Reactions go on a line of their own below the message
+            let mut lines = vec![Line::from(spans)];
+            if !m.reactions.is_empty() {
+                let summary: Vec<String> = m
+                    .reactions
+                    .iter()
+                    .map(|r| format!("{} {}", r.emoji, r.users.len()))
+                    .collect();
+                lines.push(Line::from(
+                    format!("      {}", summary.join("  ")).dark_gray(),
+                ));
+            }
+
+            // Collapsed threads only show how many replies they have
+            let hidden = match m.id {
+                Some(id) if !reply && !expanded.contains(&id) => {
+                    display.iter().filter(|r| r.parent == Some(id)).count()
+                }
+                _ => 0,
+            };
+            if hidden > 0 {
+                lines.push(Line::from(
+                    format!(
+                        "      ↳ {} repl{}",
+                        hidden,
+                        if hidden == 1 { "y" } else { "ies" }
+                    )
+                    .dark_gray(),
+                ));
+            }
+
+            Text::from(lines)
         }))
-        .block(Block::default().title(title).borders(Borders::ALL));
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(if selected_index.is_some() {
+            Style::new().reversed()
+        } else {
+            Style::new()
+        });
         frame.render_stateful_widget(msg_list, chunks[0], &mut list_state);
 
         let input_para = Paragraph::new(input.as_str())
@@ -282,86 +1066,760 @@ fn draw_terminal(
     Ok(())
 }
 
-fn listen(state: Arc<Mutex<ClientState>>, mut stream: TcpStream) -> io::Result<()> {
+/// Offer a file to `target`. Its data is uploaded once the server acknowledges the offer.
+fn send_file(
+    mut stream: &Connection,
+    state: &mut ClientState,
+    target: String,
+    path: PathBuf,
+) -> io::Result<()> {
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            state.display.push(DisplayMessage::system(format!(
+                "Can't read '{}': {}",
+                path.display(),
+                e
+            )));
+            return Ok(());
+        }
+    };
+    if data.len() as u64 > MAX_FILE_SIZE {
+        state.display.push(DisplayMessage::system(format!(
+            "Files can't be larger than {}.",
+            format_size(MAX_FILE_SIZE)
+        )));
+        return Ok(());
+    }
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let request = Request {
+        id: Some(state.next_request_id),
+        body: ClientToServer::OfferFile {
+            target,
+            name: name.clone(),
+            size: data.len() as u64,
+            sha256: hex::encode(Sha256::digest(&data)),
+        },
+    };
+    state.next_request_id += 1;
+
+    send_msg(&mut stream, state.codec, &request)?;
+    state.uploads.insert(request.id.unwrap(), data);
+    state
+        .display
+        .push(DisplayMessage::system(format!("Uploading '{}'...", name)));
+
+    Ok(())
+}
+
+/// Send the data of an offered file in chunks. The state stays locked while a
+/// chunk is written so it can't interleave with other requests on the connection.
+fn upload_file(state: &Mutex<ClientState>, stream: &Connection, transfer_id: u64, data: &[u8]) {
+    for (i, chunk) in data.chunks(FILE_CHUNK_SIZE).enumerate() {
+        let st = state.lock().unwrap();
+        let body = ClientToServer::UploadChunk {
+            transfer_id,
+            offset: (i * FILE_CHUNK_SIZE) as u64,
+            data: chunk.to_vec(),
+        };
+        if send_request(stream, st.codec, body).is_err() {
+            return;
+        }
+    }
+}
+
+/// Download an offered file to `path`, by default its name in the working directory.
+fn accept_file(
+    stream: &Connection,
+    state: &mut ClientState,
+    transfer_id: u64,
+    path: Option<PathBuf>,
+) -> io::Result<()> {
+    let Some(offer) = state.offers.get(&transfer_id) else {
+        state.display.push(DisplayMessage::system(
+            "No file with that number was offered to you.".to_string(),
+        ));
+        return Ok(());
+    };
+
+    // Never let the name chosen by the sender point outside the working directory
+    let path = path
+        .or_else(|| Path::new(&offer.name).file_name().map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(format!("download-{}", transfer_id)));
+    if path.exists() {
+        state.display.push(DisplayMessage::system(format!(
+            "'{}' already exists, please give another path.",
+            path.display()
+        )));
+        return Ok(());
+    }
+
+    send_request(
+        stream,
+        state.codec,
+        ClientToServer::AcceptFile { transfer_id },
+    )?;
+    state.display.push(DisplayMessage::system(format!(
+        "Downloading '{}' to '{}'...",
+        offer.name,
+        path.display()
+    )));
+    let offer = state.offers.remove(&transfer_id).unwrap();
+    state.downloads.insert(
+        transfer_id,
+        Download {
+            offer,
+            path,
+            data: Vec::new(),
+        },
+    );
+
+    Ok(())
+}
+
+/// Check a completely downloaded file against its checksum and save it.
+fn save_download(download: Download) -> String {
+    let Download { offer, path, data } = download;
+
+    if hex::encode(Sha256::digest(&data)) != offer.sha256 {
+        return format!("'{}' arrived damaged and was discarded.", offer.name);
+    }
+    match fs::write(&path, &data) {
+        Ok(()) => format!("Saved '{}' to '{}'.", offer.name, path.display()),
+        Err(e) => format!("Can't save '{}' to '{}': {}", offer.name, path.display(), e),
+    }
+}
+
+/// Open the chat of result number `hit` of the last search and select the message.
+fn open_search_hit(stream: &Connection, state: &mut ClientState, hit: usize) -> io::Result<()> {
+    let Some((target, message_id)) = hit.checked_sub(1).and_then(|i| state.search_hits.get(i))
+    else {
+        state.display.push(DisplayMessage::system(
+            "There is no search result with that number.".to_string(),
+        ));
+        return Ok(());
+    };
+
+    state.jump_to = Some((target.clone(), *message_id));
+    send_request(stream, state.codec, get_messages(target.clone(), None))
+}
+
+/// Select the opened search result, showing its thread if it's a reply. Older
+/// messages are loaded until it is shown or the chat has nothing older.
+fn show_search_hit(stream: &Connection, state: &mut ClientState) -> io::Result<()> {
+    let Some((target, message_id)) = state.jump_to.clone() else {
+        return Ok(());
+    };
+    if state.current_partner.as_ref() != Some(&target) {
+        // Another chat was opened in the meantime
+        state.jump_to = None;
+        return Ok(());
+    }
+
+    let parent = state
+        .display
+        .iter()
+        .find(|m| m.id == Some(message_id))
+        .map(|m| m.parent);
+    if let Some(parent) = parent {
+        state.jump_to = None;
+        state.selected = Some(message_id);
+        state.expanded.extend(parent);
+        return Ok(());
+    }
+
+    let oldest = state.display.iter().filter_map(|m| m.id).min();
+    if state.has_more && oldest.is_some_and(|oldest| oldest > message_id) {
+        return load_older_messages(stream, state);
+    }
+
+    state.jump_to = None;
+    state.display.push(DisplayMessage::system(
+        "That message isn't in the chat anymore.".to_string(),
+    ));
+    Ok(())
+}
+
+/// Request the newest messages of a chat, or those before the message with ID `before`.
+fn get_messages(target: String, before: Option<u64>) -> ClientToServer {
+    ClientToServer::GetMessages {
+        target,
+        before,
+        after: None,
+        limit: None,
+    }
+}
+
+/// Ask for the messages before the oldest one shown, if the server has any.
+fn load_older_messages(stream: &Connection, state: &mut ClientState) -> io::Result<()> {
+    if !state.has_more || state.loading_history {
+        return Ok(());
+    }
+
+    let oldest = state.display.iter().filter_map(|m| m.id).min();
+    match (state.current_partner.clone(), oldest) {
+        (Some(target), Some(oldest)) => {
+            state.loading_history = true;
+            send_request(stream, state.codec, get_messages(target, Some(oldest)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Send a message to `target` and show it as pending until the server acknowledges it.
+fn send_chat_message(
+    mut stream: &Connection,
+    state: &mut ClientState,
+    target: String,
+    content: String,
+    parent: Option<u64>,
+) {
+    let request = Request {
+        id: Some(state.next_request_id),
+        body: ClientToServer::SendMessage {
+            content: content.clone(),
+            target,
+            parent,
+        },
+    };
+    state.next_request_id += 1;
+
+    let delivery = match send_msg(&mut stream, state.codec, &request) {
+        Ok(()) => Delivery::Pending(request.id.unwrap()),
+        Err(_) => Delivery::Failed,
+    };
+    let handle = state.handle.as_ref().unwrap().to_string();
+    state.display.push(DisplayMessage {
+        id: None,
+        content,
+        sender: handle,
+        mode: DisplayMessageMode::User,
+        timestamp: Some(Utc::now().timestamp() as u64),
+        delivery: Some(delivery),
+        edited: false,
+        reactions: Vec::new(),
+        parent,
+    });
+}
+
+/// Messages in the order they are shown, each with whether it's shown as a reply.
+/// Replies follow the first message of their thread, but only while it's expanded.
+fn thread_view<'a>(
+    display: &'a [DisplayMessage],
+    expanded: &HashSet<u64>,
+) -> Vec<(&'a DisplayMessage, bool)> {
+    let roots: HashSet<u64> = display
+        .iter()
+        .filter(|m| m.parent.is_none())
+        .filter_map(|m| m.id)
+        .collect();
+
+    let mut view = Vec::new();
+    for message in display {
+        // Replies whose thread start was deleted or isn't loaded stay where they are
+        if message.parent.is_some_and(|parent| roots.contains(&parent)) {
+            continue;
+        }
+
+        view.push((message, false));
+        if let Some(id) = message.id.filter(|id| expanded.contains(id)) {
+            view.extend(
+                display
+                    .iter()
+                    .filter(|reply| reply.parent == Some(id))
+                    .map(|reply| (reply, true)),
+            );
+        }
+    }
+
+    view
+}
+
+/// The message sent with `request_id` if it's still displayed and unacknowledged.
+fn pending_message(state: &mut ClientState, request_id: u64) -> Option<&mut DisplayMessage> {
+    state
+        .display
+        .iter_mut()
+        .find(|m| matches!(m.delivery, Some(Delivery::Pending(id)) if id == request_id))
+}
+
+/// Tell the server everything up to `message_id` in the current direct chat has been seen.
+/// Rooms don't show read markers, so nothing is sent for them.
+fn mark_read(stream: &Connection, state: &ClientState, message_id: u64) -> io::Result<()> {
+    match &state.current_partner {
+        Some(target) if !is_room(target) && state.supports(capability::READ_RECEIPTS) => {
+            send_request(
+                stream,
+                state.codec,
+                ClientToServer::MarkRead {
+                    target: target.clone(),
+                    message_id,
+                },
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+fn listen(state: Arc<Mutex<ClientState>>, mut stream: Connection, codec: Codec) -> io::Result<()> {
     loop {
         let data = recv_msg(&mut stream)?
             .ok_or(io::Error::new(io::ErrorKind::ConnectionReset, "No data"))?;
 
-        match decode::<ServerToClient>(&data)? {
-            ServerToClient::Registered { handle } => {
-                // Successfully registered handle
+        match codec.decode::<ServerToClient>(&data)? {
+            ServerToClient::LoggedIn { handle } => {
+                // Successfully logged in
                 let mut st = state.lock().unwrap();
-                st.display.push(DisplayMessage {
-                    content: format!("Successfully registered as user: {}", handle),
-                    sender: "System".to_string(),
-                    mode: DisplayMessageMode::System,
-                });
+                st.display.clear();
+                st.display.push(DisplayMessage::system(format!(
+                    "Successfully logged in as user: {}",
+                    handle
+                )));
+                let help = st.help_messages();
+                st.display.extend(help);
                 st.title = format!("Console ({})", handle.clone());
                 st.handle = Some(handle);
+                st.login = None;
                 st.status = Status::InConsole;
             }
             ServerToClient::UserList { users } => {
-                // Response with a list of available user handles
+                // Response with every registered user and their presence
                 let mut st = state.lock().unwrap();
-                st.display.push(DisplayMessage {
-                    content: format!("Available users: {}", users.join(", ")),
-                    sender: "System".to_string(),
-                    mode: DisplayMessageMode::System,
-                });
+                st.display
+                    .push(DisplayMessage::system("Users:".to_string()));
+                for user in users {
+                    st.display.push(DisplayMessage::system(format!(
+                        "    {}: {}",
+                        user.handle,
+                        describe_presence(&user)
+                    )));
+                    st.users.insert(user.handle.clone(), user);
+                }
             }
-            ServerToClient::Error { message } => {
+            ServerToClient::DelayedMessages { messages } => {
+                // Messages that arrived while we were offline, summarize them per sender
                 let mut st = state.lock().unwrap();
-                st.display.push(DisplayMessage {
-                    content: format!("An error occurred: {}", message),
-                    sender: "System".to_string(),
-                    mode: DisplayMessageMode::System,
-                });
+
+                let mut senders: Vec<(String, usize)> = Vec::new();
+                for m in messages {
+                    match senders.iter_mut().find(|(s, _)| *s == m.sender) {
+                        Some((_, count)) => *count += 1,
+                        None => senders.push((m.sender, 1)),
+                    }
+                }
+
+                for (sender, count) in senders {
+                    st.display.push(DisplayMessage::system(format!(
+                            "{} sent you {} message(s) while you were offline. Join the chat using the command '/chat {}'",
+                            sender, count, sender
+                        )));
+                }
             }
-            ServerToClient::ChatMessages { partner, messages } => {
-                // The user has requested the chat messages with partner. Enter chat with this user
+            ServerToClient::PresenceChanged { user } => {
+                let mut st = state.lock().unwrap();
+                let was_offline = st
+                    .users
+                    .get(&user.handle)
+                    .is_none_or(|u| u.presence == Presence::Offline);
+                if user.presence == Presence::Offline {
+                    st.typing.retain(|u| *u != user.handle);
+                    st.display.push(DisplayMessage::system(format!(
+                        "{} went offline.",
+                        user.handle
+                    )));
+                } else if was_offline && st.users.contains_key(&user.handle) {
+                    st.display.push(DisplayMessage::system(format!(
+                        "{} came online.",
+                        user.handle
+                    )));
+                }
+                st.users.insert(user.handle.clone(), user);
+            }
+            ServerToClient::RoomList { rooms } => {
+                let mut st = state.lock().unwrap();
+                st.display.push(DisplayMessage::system(format!(
+                    "Available rooms: {}",
+                    rooms.join(", ")
+                )));
+            }
+            ServerToClient::BlockedList { handles } => {
+                let mut st = state.lock().unwrap();
+                let content = if handles.is_empty() {
+                    "You haven't blocked anyone.".to_string()
+                } else {
+                    format!("Blocked users: {}", handles.join(", "))
+                };
+                st.display.push(DisplayMessage::system(content));
+            }
+            ServerToClient::LeftRoom { room } => {
                 let mut st = state.lock().unwrap();
+                if st.current_partner.as_ref().is_some_and(|p| *p == room) {
+                    st.status = Status::InConsole;
+                    st.display.clear();
+                    st.current_partner = None;
+                    st.partner_read = None;
+                    st.typing.clear();
+                    st.selected = None;
+                    st.expanded.clear();
+                    st.has_more = false;
+                    st.loading_history = false;
+                    st.title = format!("Console ({})", st.handle.clone().unwrap());
+                }
+                st.display
+                    .push(DisplayMessage::system(format!("You left {}.", room)));
+            }
+            ServerToClient::Welcome { .. } | ServerToClient::Rejected { .. } => {
+                // Only sent in response to the handshake, which is done before listening
+            }
+            ServerToClient::ReadReceipt {
+                target,
+                reader,
+                message_id,
+            } => {
+                // Only direct chats get receipts, where the target is the reader
+                let mut st = state.lock().unwrap();
+                if target == reader && st.current_partner.as_ref() == Some(&target) {
+                    st.partner_read = st.partner_read.max(Some(message_id));
+                }
+            }
+            ServerToClient::Typing {
+                target,
+                user,
+                active,
+            } => {
+                let mut st = state.lock().unwrap();
+                if st.current_partner.as_ref() == Some(&target) {
+                    st.typing.retain(|u| *u != user);
+                    if active {
+                        st.typing.push(user);
+                    }
+                }
+            }
+            ServerToClient::MessageEdited { target, message } => {
+                let mut st = state.lock().unwrap();
+                if st.current_partner.as_ref() == Some(&target) {
+                    if let Some(shown) = st.display.iter_mut().find(|m| m.id == Some(message.id)) {
+                        shown.content = message.content;
+                        shown.edited = true;
+                    }
+                }
+            }
+            ServerToClient::SearchResults { query, hits } => {
+                let mut st = state.lock().unwrap();
+                if hits.is_empty() {
+                    st.display.push(DisplayMessage::system(format!(
+                        "No messages found for '{}'.",
+                        query
+                    )));
+                    continue;
+                }
 
+                st.display.push(DisplayMessage::system(format!(
+                    "Messages found for '{}', open one with '/open <n>':",
+                    query
+                )));
+                st.search_hits.clear();
+                for (
+                    n,
+                    SearchHit {
+                        conversation,
+                        message,
+                    },
+                ) in hits.into_iter().enumerate()
+                {
+                    st.display.push(DisplayMessage::system(format!(
+                        "    {}. {} {} [{}] {}",
+                        n + 1,
+                        conversation,
+                        format_timestamp(message.timestamp),
+                        message.sender,
+                        message.content
+                    )));
+                    st.search_hits.push((conversation, message.id));
+                }
+            }
+            ServerToClient::FileOffered { target, offer } => {
+                let mut st = state.lock().unwrap();
+                let size = format_size(offer.size);
+                let content = if st.handle.as_ref() == Some(&offer.sender) {
+                    format!("Sent '{}' ({}) to {}.", offer.name, size, target)
+                } else if is_room(&target) {
+                    format!(
+                        "{} sent '{}' ({}) to {}, save it with '/accept {} [<path>]'.",
+                        offer.sender, offer.name, size, target, offer.transfer_id
+                    )
+                } else {
+                    format!(
+                        "{} sent you '{}' ({}), save it with '/accept {} [<path>]'.",
+                        offer.sender, offer.name, size, offer.transfer_id
+                    )
+                };
+                st.display.push(DisplayMessage::system(content));
+
+                if st.handle.as_ref() != Some(&offer.sender) {
+                    st.offers.insert(offer.transfer_id, offer);
+                }
+            }
+            ServerToClient::FileChunk {
+                transfer_id,
+                offset,
+                data,
+            } => {
+                let mut st = state.lock().unwrap();
+                if let Some(download) = st.downloads.get_mut(&transfer_id) {
+                    // A chunk out of order makes the checksum fail in the end
+                    if offset == download.data.len() as u64 {
+                        download.data.extend_from_slice(&data);
+                    }
+                }
+            }
+            ServerToClient::FileComplete { transfer_id } => {
+                let mut st = state.lock().unwrap();
+                if let Some(download) = st.downloads.remove(&transfer_id) {
+                    let content = save_download(download);
+                    st.display.push(DisplayMessage::system(content));
+                }
+            }
+            ServerToClient::ReactionsChanged {
+                target,
+                message_id,
+                reactions,
+            } => {
+                let mut st = state.lock().unwrap();
+                if st.current_partner.as_ref() == Some(&target) {
+                    if let Some(shown) = st.display.iter_mut().find(|m| m.id == Some(message_id)) {
+                        shown.reactions = reactions;
+                    }
+                }
+            }
+            ServerToClient::MessageDeleted { target, message_id } => {
+                let mut st = state.lock().unwrap();
+                if st.current_partner.as_ref() == Some(&target) {
+                    st.display.retain(|m| m.id != Some(message_id));
+                    if st.selected == Some(message_id) {
+                        st.selected = None;
+                    }
+                }
+            }
+            ServerToClient::Ack {
+                request_id,
+                message_id,
+            } => {
+                let mut st = state.lock().unwrap();
+                if let Some(message) = pending_message(&mut st, request_id) {
+                    message.delivery = Some(Delivery::Sent);
+                    message.id = message_id;
+                }
+
+                // The offer of a file was accepted, upload its data next to everything else
+                if let (Some(data), Some(transfer_id)) =
+                    (st.uploads.remove(&request_id), message_id)
+                {
+                    let state = state.clone();
+                    let stream = stream.try_clone()?;
+                    thread::spawn(move || upload_file(&state, &stream, transfer_id, &data));
+                }
+            }
+            ServerToClient::Error {
+                request_id,
+                code,
+                message,
+            } => {
+                let mut st = state.lock().unwrap();
+                if let Some(message) = request_id.and_then(|id| pending_message(&mut st, id)) {
+                    message.delivery = Some(Delivery::Failed);
+                }
+                if let Some(id) = request_id {
+                    st.uploads.remove(&id);
+                }
+
+                let content = match code {
+                    ErrorCode::Unauthorized => "Wrong user name or password.".to_string(),
+                    ErrorCode::HandleTaken => {
+                        "That user name is taken, please pick another one.".to_string()
+                    }
+                    ErrorCode::UnknownUser => "There is no user with that name.".to_string(),
+                    ErrorCode::RateLimited => {
+                        "You're sending too fast, please slow down.".to_string()
+                    }
+                    _ => format!("An error occurred: {}", message),
+                };
+                st.display.push(DisplayMessage::system(content));
+
+                if let Status::Authenticating = st.status {
+                    // Login failed, start over from the user name
+                    st.login = None;
+                    st.status = Status::EnteringHandle;
+                    st.display
+                        .push(DisplayMessage::system(LOGIN_PROMPT.to_string()));
+                }
+            }
+            ServerToClient::ChatMessages {
+                partner,
+                messages,
+                partner_read,
+                has_more,
+            } => {
+                let mut st = state.lock().unwrap();
+                let handle = st.handle.clone().unwrap_or_default();
+
+                if st.loading_history && st.current_partner.as_ref() == Some(&partner) {
+                    // A page of older history, it goes above what is already shown
+                    st.loading_history = false;
+                    st.has_more = has_more;
+                    st.display.splice(
+                        0..0,
+                        messages
+                            .into_iter()
+                            .map(|m| DisplayMessage::from_message(m, &handle)),
+                    );
+                    show_search_hit(&stream, &mut st)?;
+                    continue;
+                }
+
+                // The user has requested the chat messages with partner. Enter chat with this user
                 st.current_partner = Some(partner.clone());
+                st.has_more = has_more;
+                st.loading_history = false;
+                st.partner_read = partner_read;
+                st.typing.clear();
+                st.selected = None;
+                st.expanded.clear();
+
+                // Everything is visible as soon as the chat is shown
+                if let Some(newest) = messages.last() {
+                    mark_read(&stream, &st, newest.id)?;
+                }
 
                 st.display.clear();
-                st.display
-                    .extend(messages.into_iter().map(|m| DisplayMessage {
-                        content: m.content,
-                        mode: if m.sender == partner {
-                            DisplayMessageMode::OtherUser
-                        } else if m.sender == "System" {
-                            DisplayMessageMode::System
-                        } else {
-                            DisplayMessageMode::User
-                        },
-                        sender: m.sender,
-                    }));
+                st.display.extend(
+                    messages
+                        .into_iter()
+                        .map(|m| DisplayMessage::from_message(m, &handle)),
+                );
+
+                show_search_hit(&stream, &mut st)?;
                 st.status = Status::InChat;
-                st.title = format!("In Chat with '{}'", partner);
+                st.title = if is_room(&partner) {
+                    format!("In Room '{}'", partner)
+                } else {
+                    format!("In Chat with '{}'", partner)
+                };
             }
-            ServerToClient::ChatMessage { sender, content } => {
+            ServerToClient::ChatMessage {
+                message,
+                room: Some(room),
+            } => {
                 let mut st = state.lock().unwrap();
 
-                if st.current_partner.as_ref().map_or(false, |s| *s == sender) {
-                    st.display.push(DisplayMessage {
-                        content,
-                        sender,
-                        mode: DisplayMessageMode::OtherUser,
-                    });
+                if st.current_partner.as_ref().is_some_and(|p| *p == room) {
+                    let handle = st.handle.clone().unwrap_or_default();
+                    mark_read(&stream, &st, message.id)?;
+                    st.typing.retain(|user| *user != message.sender);
+                    st.display
+                        .push(DisplayMessage::from_message(message, &handle));
                 } else {
-                    // TODO limit this to prevent excessive spam
-                    st.display.push( DisplayMessage {
-                        content: format!("{} just sent you a message. Join the chat using the command '/chat {}'", sender, sender),
-                        sender: "System".to_string(),
-                        mode: DisplayMessageMode::System,
+                    st.display.push(DisplayMessage::system(format!(
+                        "{} wrote in {}. Enter the room using the command '/join {}'",
+                        message.sender, room, room
+                    )));
+                }
+            }
+            ServerToClient::ChatMessage {
+                message,
+                room: None,
+            } => {
+                let mut st = state.lock().unwrap();
 
-                    });
+                if st
+                    .current_partner
+                    .as_ref()
+                    .is_some_and(|s| *s == message.sender)
+                {
+                    let handle = st.handle.clone().unwrap_or_default();
+                    mark_read(&stream, &st, message.id)?;
+                    st.typing.retain(|user| *user != message.sender);
+                    st.display
+                        .push(DisplayMessage::from_message(message, &handle));
+                } else {
+                    let sender = message.sender;
+                    // TODO limit this to prevent excessive spam
+                    st.display.push(DisplayMessage::system(format!(
+                        "{} just sent you a message. Join the chat using the command '/chat {}'",
+                        sender, sender
+                    )));
                 }
             }
         }
     }
 }
 
+// The chat typing is reported for, and when a key was last pressed in it
+type TypingReport = Option<(String, Instant)>;
+
+/// Report typing in the current chat, unless it's already reported.
+fn start_typing(
+    stream: &Connection,
+    client_state: &Arc<Mutex<ClientState>>,
+    typing: &mut TypingReport,
+) {
+    let state = client_state.lock().unwrap();
+    let target = match (&state.status, &state.current_partner) {
+        (Status::InChat, Some(target)) if state.supports(capability::TYPING) => target.clone(),
+        _ => return,
+    };
+
+    match typing {
+        Some((reported, _)) if *reported == target => {}
+        _ => {
+            // The user may have switched chats while typing
+            if let Some((reported, _)) = typing.take() {
+                let _ = send_request(
+                    stream,
+                    state.codec,
+                    ClientToServer::Typing {
+                        target: reported,
+                        active: false,
+                    },
+                );
+            }
+            let _ = send_request(
+                stream,
+                state.codec,
+                ClientToServer::Typing {
+                    target: target.clone(),
+                    active: true,
+                },
+            );
+        }
+    }
+
+    *typing = Some((target, Instant::now()));
+}
+
+fn stop_typing(
+    stream: &Connection,
+    client_state: &Arc<Mutex<ClientState>>,
+    typing: &mut TypingReport,
+) {
+    if let Some((target, _)) = typing.take() {
+        // Holding the lock keeps the request from interleaving with file uploads
+        let state = client_state.lock().unwrap();
+        let _ = send_request(
+            stream,
+            state.codec,
+            ClientToServer::Typing {
+                target,
+                active: false,
+            },
+        );
+    }
+}
+
 fn render(client_state: Arc<Mutex<ClientState>>) -> io::Result<()> {
     enable_raw_mode()?;
     io::stdout().execute(crossterm::terminal::EnterAlternateScreen)?;
@@ -374,33 +1832,159 @@ fn render(client_state: Arc<Mutex<ClientState>>) -> io::Result<()> {
     }
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+struct Config {
+    server: String,
+    tls: bool,
+    // Name to verify the server certificate against, defaults to the host part of `server`
+    server_name: Option<String>,
+    pinned_cert: Option<PathBuf>,
+    insecure: bool,
+    codec: Codec,
+}
 
-    let server = {
-        if args.len() < 2 {
-            "127.0.0.1:8080"
-        } else {
-            args[1].as_str()
+fn parse_args() -> io::Result<Config> {
+    let mut config = Config {
+        server: "127.0.0.1:8080".to_string(),
+        tls: false,
+        server_name: None,
+        pinned_cert: None,
+        insecure: false,
+        codec: Codec::default(),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls" => config.tls = true,
+            "--insecure" => {
+                config.tls = true;
+                config.insecure = true;
+            }
+            "--pin" => {
+                let cert = args.next().ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--pin requires a PEM file",
+                ))?;
+                config.tls = true;
+                config.pinned_cert = Some(PathBuf::from(cert));
+            }
+            "--server-name" => {
+                let name = args.next().ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--server-name requires a name",
+                ))?;
+                config.server_name = Some(name);
+            }
+            "--codec" => {
+                let name = args.next().ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--codec requires a name",
+                ))?;
+                config.codec = Codec::from_name(&name).ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown codec '{}'", name),
+                ))?;
+            }
+            _ => config.server = arg,
         }
+    }
+
+    Ok(config)
+}
+
+fn connect(config: &Config) -> io::Result<Connection> {
+    let socket = TcpStream::connect(&config.server)?;
+
+    if !config.tls {
+        return Ok(Connection::Plain(socket));
+    }
+
+    let trust = if config.insecure {
+        ServerTrust::Insecure
+    } else if let Some(cert) = &config.pinned_cert {
+        ServerTrust::pinned(cert)?
+    } else {
+        ServerTrust::WebPki
     };
 
-    let stream = TcpStream::connect(server)?;
+    let server_name = match &config.server_name {
+        Some(name) => name.clone(),
+        None => match config.server.rsplit_once(':') {
+            Some((host, _port)) => host.trim_matches(['[', ']']).to_string(),
+            None => config.server.clone(),
+        },
+    };
+
+    Connection::connect_tls(socket, transport::client_config(trust), &server_name)
+}
+
+/// Announce our protocol version, codec and capabilities. Returns the
+/// capabilities of the server, or an error if it refused the connection.
+fn handshake(mut stream: &Connection, codec: Codec) -> io::Result<Vec<String>> {
+    send_msg(
+        &mut stream,
+        Codec::Json,
+        &ClientToServer::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            codec: codec.name().to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        },
+    )?;
+
+    let data = recv_msg(&mut stream)?.ok_or(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "Server closed the connection during the handshake",
+    ))?;
+
+    match Codec::Json.decode::<ServerToClient>(&data)? {
+        ServerToClient::Welcome { capabilities, .. } => Ok(capabilities),
+        ServerToClient::Rejected { reason } => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Server rejected the connection: {}", reason),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected response to the handshake",
+        )),
+    }
+}
+
+fn main() -> io::Result<()> {
+    let config = parse_args()?;
+
+    let stream = connect(&config)?;
+    let capabilities = handshake(&stream, config.codec)?;
 
     let client_state = Arc::new(Mutex::new(ClientState {
         status: Status::Initializing,
         display: Vec::<DisplayMessage>::new(),
         title: "Connecting...".to_string(),
         handle: None,
+        login: None,
         current_partner: None,
         input: String::new(),
+        capabilities,
+        codec: config.codec,
+        next_request_id: 1,
+        partner_read: None,
+        typing: Vec::new(),
+        users: HashMap::new(),
+        selected: None,
+        expanded: HashSet::new(),
+        has_more: false,
+        loading_history: false,
+        search_hits: Vec::new(),
+        jump_to: None,
+        uploads: HashMap::new(),
+        offers: HashMap::new(),
+        downloads: HashMap::new(),
     }));
 
     let client_clone_data = client_state.clone();
     let stream_clone_data = stream.try_clone()?;
 
     thread::spawn(move || {
-        if let Err(e) = listen(client_clone_data, stream_clone_data) {
+        if let Err(e) = listen(client_clone_data, stream_clone_data, config.codec) {
             eprintln!("An error occurred in data receiving thread: {}", e);
         }
     });
@@ -413,37 +1997,66 @@ fn main() -> io::Result<()> {
         }
     });
 
+    let mut typing: TypingReport = None;
+
     loop {
         {
             let mut state = client_state.lock().unwrap();
             match state.status {
                 Status::Initializing => {
-                    // Not registred yet, do that first.
-                    state.title = "Registering".to_string();
-                    state.display.push(DisplayMessage {
-                        content: "Please enter your user name...".to_string(),
-                        sender: "System".to_string(),
-                        mode: DisplayMessageMode::System,
-                    });
-                    state.status = Status::Registering;
+                    // Not logged in yet, do that first.
+                    state.title = "Login".to_string();
+                    state
+                        .display
+                        .push(DisplayMessage::system(LOGIN_PROMPT.to_string()));
+                    state.status = Status::EnteringHandle;
                 }
                 Status::Exit => break,
                 _ => {}
             }
         }
 
+        if typing
+            .as_ref()
+            .is_some_and(|(_, last_key)| last_key.elapsed() >= TYPING_TIMEOUT)
+        {
+            stop_typing(&stream, &client_state, &mut typing);
+        }
+
+        // Wake up regularly to notice when the user stopped typing
+        if !event::poll(Duration::from_millis(250))? {
+            continue;
+        }
+
         if let event::Event::Key(key) = event::read()? {
             match key.code {
                 KeyCode::Enter => {
+                    stop_typing(&stream, &client_state, &mut typing);
                     let _ = process_input(&client_state, &stream);
                 }
                 KeyCode::Char(c) => {
-                    let mut state = client_state.lock().unwrap();
-                    state.input.push(c);
+                    client_state.lock().unwrap().input.push(c);
+                    start_typing(&stream, &client_state, &mut typing);
                 }
                 KeyCode::Backspace => {
+                    let empty = {
+                        let mut state = client_state.lock().unwrap();
+                        state.input.pop();
+                        state.input.is_empty()
+                    };
+                    if empty {
+                        stop_typing(&stream, &client_state, &mut typing);
+                    } else {
+                        start_typing(&stream, &client_state, &mut typing);
+                    }
+                }
+                KeyCode::Up | KeyCode::Down => {
                     let mut state = client_state.lock().unwrap();
-                    state.input.pop();
+                    if let Status::InChat = state.status {
+                        if state.move_selection(key.code == KeyCode::Up) {
+                            let _ = load_older_messages(&stream, &mut state);
+                        }
+                    }
                 }
                 KeyCode::Esc => break,
                 _ => {}
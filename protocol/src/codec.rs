@@ -0,0 +1,217 @@
+use crate::{Codec, FrameTooLarge, DEFAULT_MAX_FRAME_SIZE};
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::marker::PhantomData;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Read a single frame without any buffering beyond it, e.g. for the handshake
+/// before a connection is handed to a `FramedRead`. Returns `None` on a clean EOF.
+pub async fn read_frame<R>(reader: &mut R, max_frame_size: usize) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len_bytes = [0u8; 4];
+    if let Err(e) = reader.read_exact(&mut len_bytes).await {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > max_frame_size {
+        return Err(FrameTooLarge {
+            len,
+            max: max_frame_size,
+        }
+        .into());
+    }
+
+    let mut data = Vec::new();
+    reader.take(len as u64).read_to_end(&mut data).await?;
+    if data.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a frame",
+        ));
+    }
+
+    Ok(Some(data))
+}
+
+pub async fn write_frame<W>(writer: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+/// Async counterpart of `send_msg` and `recv_msg` for use with `tokio_util`'s
+/// `Framed`, `FramedRead` and `FramedWrite`. Uses the same wire format: every
+/// message is prefixed with its length as a 4 byte big-endian integer.
+///
+/// `In` is the type of the decoded messages and `Out` the type of the encoded ones,
+/// e.g. `MessageCodec<ClientToServer, ServerToClient>` on the server side.
+pub struct MessageCodec<In, Out> {
+    codec: Codec,
+    max_frame_size: usize,
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out> MessageCodec<In, Out> {
+    pub fn new(codec: Codec) -> Self {
+        Self::with_max_frame_size(codec, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Reject incoming frames larger than `max_frame_size` bytes with `FrameTooLarge`.
+    pub fn with_max_frame_size(codec: Codec, max_frame_size: usize) -> Self {
+        MessageCodec {
+            codec,
+            max_frame_size,
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> Default for MessageCodec<In, Out> {
+    fn default() -> Self {
+        Self::new(Codec::default())
+    }
+}
+
+impl<In, Out> Decoder for MessageCodec<In, Out>
+where
+    In: for<'de> Deserialize<'de>,
+{
+    type Item = In;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<In>> {
+        // The length prefix stays in `src` until the whole frame has arrived
+        let Some(len_bytes) = src.get(..4) else {
+            return Ok(None);
+        };
+
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        if len > self.max_frame_size {
+            return Err(FrameTooLarge {
+                len,
+                max: self.max_frame_size,
+            }
+            .into());
+        }
+
+        // Like `recv_msg`, let the buffer grow as data actually arrives rather than
+        // reserving whatever length the peer announced
+        if src.len() < 4 + len {
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let data = src.split_to(len);
+        self.codec.decode(&data).map(Some)
+    }
+}
+
+impl<In, Out> Encoder<Out> for MessageCodec<In, Out>
+where
+    Out: Serialize,
+{
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> io::Result<()> {
+        let data = self.codec.encode(&msg)?;
+        dst.reserve(4 + data.len());
+        dst.put_u32(data.len() as u32);
+        dst.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::is_frame_too_large;
+
+    type TestCodec = MessageCodec<String, String>;
+
+    fn frame(msg: &str) -> BytesMut {
+        let mut dst = BytesMut::new();
+        TestCodec::default()
+            .encode(msg.to_string(), &mut dst)
+            .unwrap();
+        dst
+    }
+
+    #[test]
+    fn decodes_an_encoded_frame() {
+        let mut src = frame("hello");
+        assert_eq!(
+            TestCodec::default().decode(&mut src).unwrap().as_deref(),
+            Some("hello")
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        let mut src = BytesMut::from(&(2048u32).to_be_bytes()[..]);
+        let error = TestCodec::with_max_frame_size(Codec::Json, 1024)
+            .decode(&mut src)
+            .unwrap_err();
+        assert!(is_frame_too_large(&error));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_header() {
+        let mut codec = TestCodec::default();
+        let data = frame("hello");
+        let mut src = BytesMut::from(&data[..2]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&data[2..]);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn header_on_its_own_allocates_nothing_for_the_body() {
+        let mut codec = TestCodec::default();
+        let data = frame("hello");
+        let mut src = BytesMut::from(&data[..4]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        // The header stays until the body arrives
+        assert_eq!(src.len(), 4);
+        src.extend_from_slice(&data[4..]);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn announced_length_is_not_reserved() {
+        let mut codec = TestCodec::default();
+        let mut src = BytesMut::from(&(1024u32 * 1024).to_be_bytes()[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.capacity() < 1024);
+    }
+
+    #[test]
+    fn truncated_body_waits_for_more_data() {
+        let mut codec = TestCodec::default();
+        let data = frame("hello world");
+        let mut src = BytesMut::from(&data[..8]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), 8);
+    }
+
+    #[test]
+    fn decodes_frames_arriving_together() {
+        let mut codec = TestCodec::default();
+        let mut src = frame("one");
+        src.extend_from_slice(&frame("two"));
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some("one"));
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some("two"));
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+}
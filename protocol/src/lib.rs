@@ -1,81 +1,519 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io::{self, Read, Write};
 
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(feature = "tls")]
+pub mod transport;
+
+/// Bumped whenever a change stops older and newer peers from understanding each other.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// Serialization format of the messages following the handshake, chosen by
+/// the client in `Hello`. JSON is always available, the other codecs are
+/// enabled with the feature of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    #[cfg(feature = "bincode")]
+    Bincode,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    /// Every codec compiled into this build.
+    pub const ALL: &'static [Codec] = &[
+        Codec::Json,
+        #[cfg(feature = "bincode")]
+        Codec::Bincode,
+        #[cfg(feature = "msgpack")]
+        Codec::MessagePack,
+        #[cfg(feature = "cbor")]
+        Codec::Cbor,
+    ];
+
+    /// Name used for the codec in `Hello` and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => "bincode",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|codec| codec.name() == name)
+    }
+
+    pub fn encode<T: Serialize>(self, msg: &T) -> io::Result<Vec<u8>> {
+        let result = match self {
+            Codec::Json => serde_json::to_vec(msg).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::serialize(msg).map_err(|e| e.to_string()),
+            // Structs as maps with field names, which clients in other languages expect
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(msg).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(msg, &mut data)
+                    .map(|_| data)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn decode<T: for<'de> Deserialize<'de>>(self, data: &[u8]) -> io::Result<T> {
+        let result = match self {
+            Codec::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::deserialize(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ciborium::from_reader(data).map_err(|e| e.to_string()),
+        };
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Optional features announced in `Hello` and `Welcome`. Names a peer doesn't know are ignored.
+pub mod capability {
+    pub const ROOMS: &str = "rooms";
+    pub const OFFLINE_DELIVERY: &str = "offline-delivery";
+    pub const READ_RECEIPTS: &str = "read-receipts";
+    pub const TYPING: &str = "typing";
+    pub const PRESENCE: &str = "presence";
+    pub const EDITING: &str = "editing";
+    pub const REACTIONS: &str = "reactions";
+    pub const THREADS: &str = "threads";
+    pub const SEARCH: &str = "search";
+    pub const FILES: &str = "files";
+    pub const BLOCKING: &str = "blocking";
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientToServer {
-    Register { handle: String },
+    // First message on every connection, always encoded with `Codec::Json`
+    Hello {
+        protocol_version: u32,
+        codec: String,
+        capabilities: Vec<String>,
+    },
+    CreateAccount {
+        handle: String,
+        password: String,
+    },
+    Login {
+        handle: String,
+        password: String,
+    },
+    // Also subscribes to `PresenceChanged` of every user until disconnecting
     ListUsers,
-    SendMessage { content: String, target: String },
-    GetMessages { target: String },
+    SendMessage {
+        content: String,
+        target: String,
+        // Message to reply to. The reply joins the thread of that message.
+        parent: Option<u64>,
+    },
+    // Up to `limit` messages with IDs between `after` and `before`, both exclusive.
+    // The newest of them unless only `after` is given, then the oldest.
+    GetMessages {
+        target: String,
+        before: Option<u64>,
+        after: Option<u64>,
+        limit: Option<u32>,
+    },
+    CreateRoom {
+        room: String,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    ListRooms,
+    // Everything up to and including `message_id` in the chat with `target` has been seen.
+    // Only direct chats send a `ReadReceipt` to the other participant.
+    MarkRead {
+        target: String,
+        message_id: u64,
+    },
+    // The user started or stopped typing in the chat with `target`
+    Typing {
+        target: String,
+        active: bool,
+    },
+    // Offline can't be chosen, it's set when the user disconnects
+    SetPresence {
+        presence: Presence,
+    },
+    // A short free text shown next to the presence, `None` clears it
+    SetStatus {
+        status: Option<String>,
+    },
+    // Only the sender of a message can edit or delete it
+    EditMessage {
+        target: String,
+        message_id: u64,
+        content: String,
+    },
+    DeleteMessage {
+        target: String,
+        message_id: u64,
+    },
+    // Add (`active`) or take back a reaction to any message in the chat with `target`
+    React {
+        target: String,
+        message_id: u64,
+        emoji: String,
+        active: bool,
+    },
+    // Messages containing every word of `query`, in the chat with `conversation`
+    // or all chats of the user
+    Search {
+        query: String,
+        conversation: Option<String>,
+    },
+    // Announce a file for the chat with `target`. The `Ack` carries the ID of the
+    // transfer in `message_id`, the data follows in `UploadChunk`s and the file is
+    // offered to the other participants once all of it arrived and matches `sha256`.
+    OfferFile {
+        target: String,
+        name: String,
+        size: u64,
+        // Hex encoded SHA-256 of the whole file
+        sha256: String,
+    },
+    // The next part of an offered file, `offset` has to follow on the previous chunk
+    UploadChunk {
+        transfer_id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    // Download an offered file, answered with `FileChunk`s and a `FileComplete`
+    AcceptFile {
+        transfer_id: u64,
+    },
+    // Stop (`active`) or resume receiving anything from `handle`. The blocked
+    // user isn't told, their messages are just never delivered.
+    Block {
+        handle: String,
+        active: bool,
+    },
+    ListBlocked,
+}
+
+/// Envelope of every message a client sends after the handshake. A request
+/// with an `id` is answered with an `Ack` or an `Error` carrying the same ID.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+    pub id: Option<u64>,
+    pub body: ClientToServer,
+}
+
+impl From<ClientToServer> for Request {
+    fn from(body: ClientToServer) -> Self {
+        Request { id: None, body }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerToClient {
-    Registered {
+    // Answers to `Hello`, always encoded with `Codec::Json`
+    Welcome {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    Rejected {
+        reason: String,
+    },
+    LoggedIn {
         handle: String,
     },
+    // Every registered user, whether online or not, except those the user blocked
     UserList {
-        users: Vec<String>,
+        users: Vec<UserInfo>,
     },
     ChatMessages {
         partner: String,
         messages: Vec<Message>,
+        // ID of the last message the partner has seen, for direct chats
+        partner_read: Option<u64>,
+        // More messages exist beyond the returned ones in the requested direction
+        has_more: bool,
     },
     ChatMessage {
-        sender: String,
-        content: String,
+        message: Message,
+        // Set when the message was sent to a room rather than directly
+        room: Option<String>,
+    },
+    // Messages that were sent while the recipient was offline
+    DelayedMessages {
+        messages: Vec<Message>,
+    },
+    // A user logged in or out, or changed their presence or status. Sent to those
+    // sharing a chat or room with the user, and to everyone after `ListUsers`.
+    PresenceChanged {
+        user: UserInfo,
+    },
+    RoomList {
+        rooms: Vec<String>,
+    },
+    LeftRoom {
+        room: String,
+    },
+    // `reader` has seen everything up to `message_id` in the direct chat with `target`,
+    // which is the reader. Not sent for rooms.
+    ReadReceipt {
+        target: String,
+        reader: String,
+        message_id: u64,
+    },
+    // `user` started or stopped typing in the chat with `target`, named like in `ReadReceipt`
+    Typing {
+        target: String,
+        user: String,
+        active: bool,
+    },
+    // A message in the chat with `target`, as the receiving client knows it, was
+    // edited or deleted. Also sent to the user who made the change.
+    MessageEdited {
+        target: String,
+        message: Message,
+    },
+    MessageDeleted {
+        target: String,
+        message_id: u64,
+    },
+    // The reactions to a message changed, named like in `MessageEdited`
+    ReactionsChanged {
+        target: String,
+        message_id: u64,
+        reactions: Vec<Reaction>,
+    },
+    // Newest matches first
+    SearchResults {
+        query: String,
+        hits: Vec<SearchHit>,
+    },
+    // A file was uploaded to the chat with `target`, named like in `ReadReceipt`.
+    // Also sent to the uploader once the server verified the file.
+    FileOffered {
+        target: String,
+        offer: FileOffer,
+    },
+    FileChunk {
+        transfer_id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    // Every chunk of an accepted file has been sent
+    FileComplete {
+        transfer_id: u64,
+    },
+    // Answers `ListBlocked` and every `Block`
+    BlockedList {
+        handles: Vec<String>,
+    },
+    // A request with an ID succeeded. `message_id` is set when it stored a message,
+    // and to the ID of the transfer for `OfferFile`.
+    Ack {
+        request_id: u64,
+        message_id: Option<u64>,
     },
     Error {
+        // ID of the failed request, if it had one
+        request_id: Option<u64>,
+        code: ErrorCode,
+        // Human readable description, clients should match on `code` instead
         message: String,
     },
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserInfo {
+    pub handle: String,
+    pub presence: Presence,
+    pub status: Option<String>,
+    // Seconds since the Unix epoch (UTC) at which an offline user was last connected
+    pub last_seen: Option<u64>,
+}
+
+/// Why a request failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Another account already uses the handle.
+    HandleTaken,
+    /// The handle is empty, contains whitespace or looks like a room.
+    InvalidHandle,
+    InvalidPassword,
+    /// Unknown handle or wrong password. Deliberately doesn't say which.
+    Unauthorized,
+    /// The request needs a logged in session.
+    NotRegistered,
+    /// The session is already logged in, or the handle is in use by another session.
+    AlreadyLoggedIn,
+    /// No account exists with the target handle.
+    UnknownUser,
+    InvalidRoomName,
+    RoomExists,
+    UnknownRoom,
+    /// The request needs membership of the room.
+    NotAMember,
+    /// No message with the ID exists in the conversation.
+    UnknownMessage,
+    /// Only the sender of a message can change it.
+    NotSender,
+    /// No file transfer with the ID is in progress or offered to the user.
+    UnknownTransfer,
+    /// The request is well formed but its values aren't allowed.
+    InvalidRequest,
+    /// Too many requests in a short time, try again later.
+    RateLimited,
+    /// Something went wrong on the server, e.g. writing to its storage.
+    Internal,
+}
+
+/// Sender that clients show notices under. No account can use it as its handle.
+pub const SYSTEM_SENDER: &str = "System";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
+    // Assigned by the server, increasing with every stored message. Zero in
+    // history logged before IDs existed, the server numbers those on startup.
+    #[serde(default)]
+    pub id: u64,
+    // Seconds since the Unix epoch (UTC) at which the server received the message
+    #[serde(default)]
+    pub timestamp: u64,
     pub sender: String,
     pub content: String,
+    // Seconds since the Unix epoch (UTC) of the last edit, if the message was edited
+    #[serde(default)]
+    pub edited: Option<u64>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // First message of the thread this message replies in. Threads are one level
+    // deep, a reply to a reply gets the same parent as the message it answers.
+    #[serde(default)]
+    pub parent: Option<u64>,
 }
 
-#[cfg(feature = "json")]
-macro_rules! serialize {
-    ($msg:expr) => {{
-        serde_json::to_vec($msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
-    }};
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    // The chat the message is in, named like the `target` of requests
+    pub conversation: String,
+    pub message: Message,
+}
+
+/// A file the server holds for the participants of a chat.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileOffer {
+    pub transfer_id: u64,
+    pub sender: String,
+    // File name without any directories
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
 }
 
-#[cfg(feature = "bincode")]
-macro_rules! serialize {
-    ($msg:expr) => {{
-        bincode::serialize($msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
-    }};
+/// Largest file that can be offered, in bytes.
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// Most data in a single chunk of a file. Small enough that a chunk stays well
+/// below the frame size limits even in JSON, where every byte becomes a number.
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Everyone who reacted to a message with the same emoji, in the order they did.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<String>,
 }
 
-#[cfg(feature = "json")]
-macro_rules! deserialize {
-    ($data:expr) => {{
-        serde_json::from_slice($data)
-    }};
+/// Largest frame `recv_msg` accepts. Peers announcing more are rejected before
+/// anything is allocated for the frame.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// A peer announced a frame larger than the configured maximum. Returned as the
+/// inner error of an `io::Error` of kind `InvalidData`, see `is_frame_too_large`.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub len: usize,
+    pub max: usize,
 }
 
-#[cfg(feature = "bincode")]
-macro_rules! deserialize {
-    ($data:expr) => {{
-        bincode::deserialize($data)
-    }};
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame of {} bytes exceeds the maximum of {} bytes",
+            self.len, self.max
+        )
+    }
 }
 
-pub fn send_msg<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> io::Result<()> {
-    let data = serialize!(msg);
+impl std::error::Error for FrameTooLarge {}
 
+impl From<FrameTooLarge> for io::Error {
+    fn from(e: FrameTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+pub fn is_frame_too_large(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<FrameTooLarge>())
+}
+
+/// Rooms share the `target` field with user handles and are told apart by a leading '#'.
+pub fn is_room(target: &str) -> bool {
+    target.starts_with('#')
+}
+
+pub fn send_msg<W: Write, T: Serialize>(writer: &mut W, codec: Codec, msg: &T) -> io::Result<()> {
+    let data = codec.encode(msg)?;
     let len = data.len() as u32;
     writer.write_all(&len.to_be_bytes())?;
     writer.write_all(&data)?;
-    writer.flush()?;
-
-    Ok(())
+    writer.flush()
 }
 
 pub fn recv_msg<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    recv_msg_limited(reader, DEFAULT_MAX_FRAME_SIZE)
+}
+
+/// Like `recv_msg`, but rejects frames larger than `max_frame_size` bytes.
+pub fn recv_msg_limited<R: Read>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
     let mut len_bytes = [0u8; 4];
 
     // Every message is prefixed with the number of bytes the message is
@@ -87,13 +525,455 @@ pub fn recv_msg<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
     }
 
     let len = u32::from_be_bytes(len_bytes) as usize;
-    let mut data = vec![0u8; len];
-    reader.read_exact(&mut data)?;
+    if len > max_frame_size {
+        return Err(FrameTooLarge {
+            len,
+            max: max_frame_size,
+        }
+        .into());
+    }
+
+    // Grow the buffer as data actually arrives rather than trusting the announced length
+    let mut data = Vec::new();
+    reader.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a frame",
+        ));
+    }
 
     Ok(Some(data))
 }
 
-pub fn decode<T: for<'de> Deserialize<'de>>(data: &[u8]) -> io::Result<T> {
-    let result = deserialize!(data);
-    result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn recv_msg_reads_a_frame() {
+        let data = frame(b"hello");
+        let mut reader = &data[..];
+        assert_eq!(recv_msg(&mut reader).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(recv_msg(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn recv_msg_rejects_oversized_header() {
+        let header = (u32::MAX).to_be_bytes();
+        let error = recv_msg_limited(&mut &header[..], 1024).unwrap_err();
+        assert!(is_frame_too_large(&error));
+    }
+
+    #[test]
+    fn recv_msg_accepts_frame_of_exactly_the_limit() {
+        let data = frame(&[7; 16]);
+        assert_eq!(
+            recv_msg_limited(&mut &data[..], 16).unwrap(),
+            Some(vec![7; 16])
+        );
+    }
+
+    #[test]
+    fn recv_msg_fails_on_truncated_body() {
+        let mut data = frame(b"hello");
+        data.truncate(6);
+        let error = recv_msg(&mut &data[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!is_frame_too_large(&error));
+    }
+
+    #[test]
+    fn recv_msg_fails_on_header_without_body() {
+        let header = 5u32.to_be_bytes();
+        let error = recv_msg(&mut &header[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn recv_msg_waits_for_body_sent_after_header() {
+        let data = frame(b"hello");
+        // Two separate reads, like a header and body arriving in different packets
+        let mut reader = (&data[..4]).chain(&data[4..]);
+        assert_eq!(recv_msg(&mut reader).unwrap(), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn recv_msg_treats_partial_header_as_eof() {
+        let mut reader = &[0u8, 0][..];
+        assert_eq!(recv_msg(&mut reader).unwrap(), None);
+    }
+
+    fn message(id: u64) -> Message {
+        Message {
+            id,
+            timestamp: 1_700_000_000 + id,
+            sender: "alice".to_string(),
+            content: "hello, wörld 👋".to_string(),
+            edited: Some(1_700_000_100),
+            reactions: vec![Reaction {
+                emoji: "👍".to_string(),
+                users: vec!["bob".to_string(), "carol".to_string()],
+            }],
+            parent: Some(1),
+        }
+    }
+
+    fn user() -> UserInfo {
+        UserInfo {
+            handle: "bob".to_string(),
+            presence: Presence::Away,
+            status: Some("lunch".to_string()),
+            last_seen: None,
+        }
+    }
+
+    fn offer() -> FileOffer {
+        FileOffer {
+            transfer_id: 3,
+            sender: "alice".to_string(),
+            name: "cat.png".to_string(),
+            size: 4,
+            sha256: "ab".repeat(32),
+        }
+    }
+
+    const ERROR_CODES: &[ErrorCode] = &[
+        ErrorCode::HandleTaken,
+        ErrorCode::InvalidHandle,
+        ErrorCode::InvalidPassword,
+        ErrorCode::Unauthorized,
+        ErrorCode::NotRegistered,
+        ErrorCode::AlreadyLoggedIn,
+        ErrorCode::UnknownUser,
+        ErrorCode::InvalidRoomName,
+        ErrorCode::RoomExists,
+        ErrorCode::UnknownRoom,
+        ErrorCode::NotAMember,
+        ErrorCode::UnknownMessage,
+        ErrorCode::NotSender,
+        ErrorCode::UnknownTransfer,
+        ErrorCode::InvalidRequest,
+        ErrorCode::RateLimited,
+        ErrorCode::Internal,
+    ];
+
+    /// One of every request. A new variant fails to compile here until it's added.
+    fn client_messages() -> Vec<ClientToServer> {
+        let messages = vec![
+            ClientToServer::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                codec: "json".to_string(),
+                capabilities: vec![capability::ROOMS.to_string()],
+            },
+            ClientToServer::CreateAccount {
+                handle: "alice".to_string(),
+                password: "secret".to_string(),
+            },
+            ClientToServer::Login {
+                handle: "alice".to_string(),
+                password: "secret".to_string(),
+            },
+            ClientToServer::ListUsers,
+            ClientToServer::SendMessage {
+                content: "hi".to_string(),
+                target: "#room".to_string(),
+                parent: Some(2),
+            },
+            ClientToServer::GetMessages {
+                target: "bob".to_string(),
+                before: Some(10),
+                after: None,
+                limit: Some(20),
+            },
+            ClientToServer::CreateRoom {
+                room: "#room".to_string(),
+            },
+            ClientToServer::JoinRoom {
+                room: "#room".to_string(),
+            },
+            ClientToServer::LeaveRoom {
+                room: "#room".to_string(),
+            },
+            ClientToServer::ListRooms,
+            ClientToServer::MarkRead {
+                target: "bob".to_string(),
+                message_id: 4,
+            },
+            ClientToServer::Typing {
+                target: "bob".to_string(),
+                active: true,
+            },
+            ClientToServer::SetPresence {
+                presence: Presence::DoNotDisturb,
+            },
+            ClientToServer::SetStatus { status: None },
+            ClientToServer::EditMessage {
+                target: "bob".to_string(),
+                message_id: 4,
+                content: "fixed".to_string(),
+            },
+            ClientToServer::DeleteMessage {
+                target: "bob".to_string(),
+                message_id: 4,
+            },
+            ClientToServer::React {
+                target: "bob".to_string(),
+                message_id: 4,
+                emoji: "🎉".to_string(),
+                active: false,
+            },
+            ClientToServer::Search {
+                query: "lunch".to_string(),
+                conversation: Some("bob".to_string()),
+            },
+            ClientToServer::OfferFile {
+                target: "bob".to_string(),
+                name: "cat.png".to_string(),
+                size: 4,
+                sha256: "ab".repeat(32),
+            },
+            ClientToServer::UploadChunk {
+                transfer_id: 3,
+                offset: 0,
+                data: vec![0, 1, 254, 255],
+            },
+            ClientToServer::AcceptFile { transfer_id: 3 },
+            ClientToServer::Block {
+                handle: "mallory".to_string(),
+                active: true,
+            },
+            ClientToServer::ListBlocked,
+        ];
+
+        for message in &messages {
+            match message {
+                ClientToServer::Hello { .. }
+                | ClientToServer::CreateAccount { .. }
+                | ClientToServer::Login { .. }
+                | ClientToServer::ListUsers
+                | ClientToServer::SendMessage { .. }
+                | ClientToServer::GetMessages { .. }
+                | ClientToServer::CreateRoom { .. }
+                | ClientToServer::JoinRoom { .. }
+                | ClientToServer::LeaveRoom { .. }
+                | ClientToServer::ListRooms
+                | ClientToServer::MarkRead { .. }
+                | ClientToServer::Typing { .. }
+                | ClientToServer::SetPresence { .. }
+                | ClientToServer::SetStatus { .. }
+                | ClientToServer::EditMessage { .. }
+                | ClientToServer::DeleteMessage { .. }
+                | ClientToServer::React { .. }
+                | ClientToServer::Search { .. }
+                | ClientToServer::OfferFile { .. }
+                | ClientToServer::UploadChunk { .. }
+                | ClientToServer::AcceptFile { .. }
+                | ClientToServer::Block { .. }
+                | ClientToServer::ListBlocked => {}
+            }
+        }
+        messages
+    }
+
+    /// One of every server message, with an `Error` for every code.
+    fn server_messages() -> Vec<ServerToClient> {
+        let mut messages = vec![
+            ServerToClient::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![capability::SEARCH.to_string()],
+            },
+            ServerToClient::Rejected {
+                reason: "outdated".to_string(),
+            },
+            ServerToClient::LoggedIn {
+                handle: "alice".to_string(),
+            },
+            ServerToClient::UserList {
+                users: vec![user()],
+            },
+            ServerToClient::ChatMessages {
+                partner: "bob".to_string(),
+                messages: vec![message(1), message(2)],
+                partner_read: Some(1),
+                has_more: true,
+            },
+            ServerToClient::ChatMessage {
+                message: message(3),
+                room: Some("#room".to_string()),
+            },
+            ServerToClient::DelayedMessages {
+                messages: vec![message(4)],
+            },
+            ServerToClient::PresenceChanged { user: user() },
+            ServerToClient::RoomList {
+                rooms: vec!["#room".to_string()],
+            },
+            ServerToClient::LeftRoom {
+                room: "#room".to_string(),
+            },
+            ServerToClient::ReadReceipt {
+                target: "bob".to_string(),
+                reader: "bob".to_string(),
+                message_id: 4,
+            },
+            ServerToClient::Typing {
+                target: "#room".to_string(),
+                user: "bob".to_string(),
+                active: true,
+            },
+            ServerToClient::MessageEdited {
+                target: "bob".to_string(),
+                message: message(5),
+            },
+            ServerToClient::MessageDeleted {
+                target: "bob".to_string(),
+                message_id: 5,
+            },
+            ServerToClient::ReactionsChanged {
+                target: "bob".to_string(),
+                message_id: 5,
+                reactions: message(5).reactions,
+            },
+            ServerToClient::SearchResults {
+                query: "hello".to_string(),
+                hits: vec![SearchHit {
+                    conversation: "bob".to_string(),
+                    message: message(6),
+                }],
+            },
+            ServerToClient::FileOffered {
+                target: "alice".to_string(),
+                offer: offer(),
+            },
+            ServerToClient::FileChunk {
+                transfer_id: 3,
+                offset: 0,
+                data: (0..=255).collect(),
+            },
+            ServerToClient::FileComplete { transfer_id: 3 },
+            ServerToClient::BlockedList {
+                handles: vec!["mallory".to_string()],
+            },
+            ServerToClient::Ack {
+                request_id: 7,
+                message_id: None,
+            },
+        ];
+        messages.extend(ERROR_CODES.iter().map(|&code| ServerToClient::Error {
+            request_id: Some(7),
+            code,
+            message: "failed".to_string(),
+        }));
+
+        for message in &messages {
+            match message {
+                ServerToClient::Welcome { .. }
+                | ServerToClient::Rejected { .. }
+                | ServerToClient::LoggedIn { .. }
+                | ServerToClient::UserList { .. }
+                | ServerToClient::ChatMessages { .. }
+                | ServerToClient::ChatMessage { .. }
+                | ServerToClient::DelayedMessages { .. }
+                | ServerToClient::PresenceChanged { .. }
+                | ServerToClient::RoomList { .. }
+                | ServerToClient::LeftRoom { .. }
+                | ServerToClient::ReadReceipt { .. }
+                | ServerToClient::Typing { .. }
+                | ServerToClient::MessageEdited { .. }
+                | ServerToClient::MessageDeleted { .. }
+                | ServerToClient::ReactionsChanged { .. }
+                | ServerToClient::SearchResults { .. }
+                | ServerToClient::FileOffered { .. }
+                | ServerToClient::FileChunk { .. }
+                | ServerToClient::FileComplete { .. }
+                | ServerToClient::BlockedList { .. }
+                | ServerToClient::Ack { .. }
+                | ServerToClient::Error { .. } => {}
+            }
+        }
+        messages
+    }
+
+    /// Encode and decode `msg` with `codec`, comparing both through their JSON form
+    /// since the message types don't implement `PartialEq`.
+    fn assert_round_trip<T>(codec: Codec, msg: &T)
+    where
+        T: Serialize + for<'de> Deserialize<'de> + fmt::Debug,
+    {
+        let data = codec.encode(msg).unwrap();
+        let decoded: T = codec
+            .decode(&data)
+            .unwrap_or_else(|e| panic!("{} failed to decode {:?}: {}", codec, msg, e));
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(msg).unwrap(),
+            "{} changed {:?}",
+            codec,
+            msg
+        );
+    }
+
+    #[test]
+    fn every_request_round_trips_with_every_codec() {
+        for &codec in Codec::ALL {
+            for (id, body) in client_messages().into_iter().enumerate() {
+                assert_round_trip(codec, &body);
+                let id = Some(id as u64).filter(|id| id % 2 == 0);
+                assert_round_trip(codec, &Request { id, body });
+            }
+        }
+    }
+
+    #[test]
+    fn every_server_message_round_trips_with_every_codec() {
+        for &codec in Codec::ALL {
+            for msg in server_messages() {
+                assert_round_trip(codec, &msg);
+            }
+        }
+    }
+
+    #[test]
+    fn every_error_code_round_trips_with_every_codec() {
+        for &codec in Codec::ALL {
+            for &code in ERROR_CODES {
+                let data = codec.encode(&code).unwrap();
+                assert_eq!(codec.decode::<ErrorCode>(&data).unwrap(), code);
+            }
+        }
+    }
+
+    #[test]
+    fn codec_names_round_trip() {
+        for &codec in Codec::ALL {
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(Codec::from_name("xml"), None);
+    }
+
+    #[test]
+    fn message_fields_added_later_default_when_missing() {
+        let json = br#"{"sender":"alice","content":"from an old log"}"#;
+        let message: Message = Codec::Json.decode(json).unwrap();
+        assert_eq!(message.id, 0);
+        assert_eq!(message.timestamp, 0);
+        assert_eq!(message.edited, None);
+        assert!(message.reactions.is_empty());
+        assert_eq!(message.parent, None);
+    }
+
+    #[test]
+    fn garbage_fails_to_decode_with_invalid_data() {
+        for &codec in Codec::ALL {
+            let error = codec.decode::<ServerToClient>(&[0xff; 3]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
 }
@@ -0,0 +1,322 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A connection to a peer, either plaintext or TLS encrypted. Like `TcpStream`
+/// it can be cloned so one thread reads while others write.
+#[derive(Debug)]
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Connection {
+    /// Perform the client side of a TLS handshake on a connected socket.
+    pub fn connect_tls(
+        socket: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let conn = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+        TlsStream::handshake(socket, conn.into()).map(Connection::Tls)
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Connection::Plain(stream) => stream.try_clone().map(Connection::Plain),
+            Connection::Tls(stream) => stream.try_clone().map(Connection::Tls),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket().peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket().shutdown(how)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => &stream.socket,
+        }
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.read(buf)
+            }
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.write(buf)
+            }
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.flush()
+            }
+            Connection::Tls(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[derive(Debug)]
+struct TlsState {
+    conn: rustls::Connection,
+    // Decrypted data that hasn't been read yet
+    plaintext: VecDeque<u8>,
+}
+
+/// TLS over a `TcpStream` that can be shared between a reading and writing
+/// threads. Reading from the socket happens without holding the lock, so a
+/// blocked reader never stalls writers.
+#[derive(Debug)]
+pub struct TlsStream {
+    socket: TcpStream,
+    state: Arc<Mutex<TlsState>>,
+}
+
+impl TlsStream {
+    fn handshake(mut socket: TcpStream, mut conn: rustls::Connection) -> io::Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+
+        Ok(TlsStream {
+            socket,
+            state: Arc::new(Mutex::new(TlsState {
+                conn,
+                plaintext: VecDeque::new(),
+            })),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream {
+            socket: self.socket.try_clone()?,
+            state: self.state.clone(),
+        })
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0u8; 16 * 1024];
+
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if !state.plaintext.is_empty() {
+                    return state.plaintext.read(buf);
+                }
+            }
+
+            let n = (&self.socket).read(&mut received)?;
+
+            let mut state = self.state.lock().unwrap();
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut records = &received[..n];
+            while !records.is_empty() {
+                state.conn.read_tls(&mut records)?;
+                let io_state = state
+                    .conn
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                let mut plaintext = vec![0u8; io_state.plaintext_bytes_to_read()];
+                state.conn.reader().read_exact(&mut plaintext)?;
+                state.plaintext.extend(plaintext);
+
+                if io_state.peer_has_closed() && state.plaintext.is_empty() {
+                    return Ok(0);
+                }
+            }
+
+            // Processing may have produced alerts or key updates for the peer
+            while state.conn.wants_write() {
+                state.conn.write_tls(&mut &self.socket)?;
+            }
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.conn.writer().write_all(buf)?;
+
+        while state.conn.wants_write() {
+            state.conn.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(buf.len())
+    }
+}
+
+/// Build the server TLS configuration from PEM encoded certificate chain and private key files.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(Arc::new(config))
+}
+
+/// How the client decides whether to trust the server's certificate.
+pub enum ServerTrust {
+    /// Verify against the bundled Mozilla root certificates.
+    WebPki,
+    /// Only accept the exact certificate in the given PEM file, for self-signed setups.
+    Pinned(CertificateDer<'static>),
+    /// Accept any certificate. Only for testing, this allows man-in-the-middle attacks.
+    Insecure,
+}
+
+impl ServerTrust {
+    pub fn pinned(cert_path: &Path) -> io::Result<Self> {
+        let cert = CertificateDer::from_pem_file(cert_path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(ServerTrust::Pinned(cert))
+    }
+}
+
+pub fn client_config(trust: ServerTrust) -> Arc<ClientConfig> {
+    let builder = ClientConfig::builder();
+
+    let config = match trust {
+        ServerTrust::WebPki => {
+            let roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        ServerTrust::Pinned(cert) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(FixedCertVerifier::new(Some(cert))))
+            .with_no_client_auth(),
+        ServerTrust::Insecure => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(FixedCertVerifier::new(None)))
+            .with_no_client_auth(),
+    };
+
+    Arc::new(config)
+}
+
+/// Accepts the server certificate if it equals the pinned one, or any certificate
+/// if nothing is pinned. Handshake signatures are still checked.
+#[derive(Debug)]
+struct FixedCertVerifier {
+    pinned: Option<CertificateDer<'static>>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl FixedCertVerifier {
+    fn new(pinned: Option<CertificateDer<'static>>) -> Self {
+        FixedCertVerifier {
+            pinned,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl ServerCertVerifier for FixedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.pinned {
+            Some(pinned) if pinned.as_ref() != end_entity.as_ref() => Err(
+                rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer),
+            ),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,124 @@
+use crate::storage::JsonLog;
+use pbkdf2::pbkdf2_hmac_array;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+const ACCOUNTS_FILE_NAME: &str = "accounts.log";
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// A salted password hash, both hex encoded.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Credentials {
+    salt: String,
+    hash: String,
+}
+
+impl Credentials {
+    /// Hash `password` with a freshly generated salt.
+    pub fn new(password: &str) -> io::Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(io::Error::other)?;
+
+        Ok(Credentials {
+            salt: hex::encode(salt),
+            hash: hex::encode(hash_password(password, &salt)),
+        })
+    }
+
+    /// Credentials no password matches. Checked for handles without an account, so
+    /// logging in as one takes as long as a wrong password and doesn't reveal it.
+    pub fn dummy() -> Self {
+        Credentials {
+            salt: hex::encode([0u8; SALT_LEN]),
+            hash: hex::encode([0u8; HASH_LEN]),
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let (Ok(salt), Ok(expected)) = (hex::decode(&self.salt), hex::decode(&self.hash)) else {
+            return false;
+        };
+
+        let hash = hash_password(password, &salt);
+
+        // Compare every byte so the time taken doesn't reveal where they differ
+        expected.len() == hash.len()
+            && expected
+                .iter()
+                .zip(hash.iter())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    handle: String,
+    #[serde(flatten)]
+    credentials: Credentials,
+}
+
+/// Registered accounts with their salted password hashes. When opened with a
+/// data directory every new account is appended to a log in it.
+pub struct Accounts {
+    accounts: HashMap<String, Credentials>,
+    log: Option<JsonLog>,
+}
+
+impl Accounts {
+    pub fn open(data_dir: Option<&Path>) -> io::Result<Self> {
+        let mut accounts = HashMap::new();
+
+        let Some(data_dir) = data_dir else {
+            return Ok(Accounts {
+                accounts,
+                log: None,
+            });
+        };
+
+        let log = JsonLog::open(data_dir, ACCOUNTS_FILE_NAME, |account: Account| {
+            accounts.insert(account.handle, account.credentials);
+            Ok(())
+        })?;
+
+        Ok(Accounts {
+            accounts,
+            log: Some(log),
+        })
+    }
+
+    pub fn exists(&self, handle: &str) -> bool {
+        self.accounts.contains_key(handle)
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = &String> {
+        self.accounts.keys()
+    }
+
+    pub fn credentials(&self, handle: &str) -> Option<Credentials> {
+        self.accounts.get(handle).cloned()
+    }
+
+    pub fn insert(&mut self, handle: &str, credentials: Credentials) -> io::Result<()> {
+        let account = Account {
+            handle: handle.to_string(),
+            credentials,
+        };
+
+        if let Some(log) = &mut self.log {
+            log.append(&account)?;
+        }
+
+        self.accounts.insert(account.handle, account.credentials);
+        Ok(())
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> [u8; HASH_LEN] {
+    pbkdf2_hmac_array::<Sha256, HASH_LEN>(password.as_bytes(), salt, PBKDF2_ROUNDS)
+}
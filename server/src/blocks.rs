@@ -0,0 +1,87 @@
+use crate::storage::JsonLog;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+
+const BLOCKS_FILE_NAME: &str = "blocks.log";
+
+/// A user blocking (`active`) or unblocking another.
+#[derive(Serialize, Deserialize)]
+struct BlockEntry {
+    user: String,
+    blocked: String,
+    active: bool,
+}
+
+/// The handles every user has blocked. When opened with a data directory every
+/// change is appended to a log in it.
+pub struct BlockLists {
+    blocked: HashMap<String, BTreeSet<String>>,
+    log: Option<JsonLog>,
+}
+
+impl BlockLists {
+    pub fn open(data_dir: Option<&Path>) -> io::Result<Self> {
+        let mut lists = BlockLists {
+            blocked: HashMap::new(),
+            log: None,
+        };
+
+        let Some(data_dir) = data_dir else {
+            return Ok(lists);
+        };
+
+        let log = JsonLog::open(data_dir, BLOCKS_FILE_NAME, |entry| {
+            lists.apply(entry);
+            Ok(())
+        })?;
+        lists.log = Some(log);
+
+        Ok(lists)
+    }
+
+    /// Whether `user` has blocked `sender`.
+    pub fn is_blocked(&self, user: &str, sender: &str) -> bool {
+        self.blocked
+            .get(user)
+            .is_some_and(|blocked| blocked.contains(sender))
+    }
+
+    /// Everyone `user` has blocked, sorted by handle.
+    pub fn blocked(&self, user: &str) -> Vec<String> {
+        self.blocked
+            .get(user)
+            .map(|blocked| blocked.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Block or unblock `handle` for `user`. Changes nothing if it already was.
+    pub fn set(&mut self, user: &str, handle: &str, active: bool) -> io::Result<()> {
+        if self.is_blocked(user, handle) == active {
+            return Ok(());
+        }
+
+        let entry = BlockEntry {
+            user: user.to_string(),
+            blocked: handle.to_string(),
+            active,
+        };
+
+        if let Some(log) = &mut self.log {
+            log.append(&entry)?;
+        }
+
+        self.apply(entry);
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: BlockEntry) {
+        let blocked = self.blocked.entry(entry.user).or_default();
+        if entry.active {
+            blocked.insert(entry.blocked);
+        } else {
+            blocked.remove(&entry.blocked);
+        }
+    }
+}
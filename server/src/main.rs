@@ -1,203 +1,1858 @@
-use protocol::{decode, recv_msg, send_msg, ClientToServer, Message, ServerToClient};
-use std::collections::HashMap;
+mod accounts;
+mod blocks;
+mod profiles;
+mod rooms;
+mod search;
+mod storage;
+
+use accounts::{Accounts, Credentials};
+use blocks::BlockLists;
+use futures::{SinkExt, StreamExt};
+use profiles::{Profile, Profiles};
+use protocol::codec::{read_frame, write_frame, MessageCodec};
+use protocol::transport;
+use protocol::{
+    capability, is_room, ClientToServer, Codec, ErrorCode, FileOffer, Message, Presence, Request,
+    SearchHit, ServerToClient, UserInfo, FILE_CHUNK_SIZE, MAX_FILE_SIZE, PROTOCOL_VERSION,
+    SYSTEM_SENDER,
+};
+use rooms::Rooms;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::io;
-use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use storage::{unix_time, ChatKey, ChatStore, LogStore, MemoryStore};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{self, Instant};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug)]
-struct Client {
-    stream: TcpStream,
+type ClientFrames<R> = FramedRead<FrameTimer<R>, MessageCodec<Request, ServerToClient>>;
+type ServerFrames<W> = FramedWrite<W, MessageCodec<Request, ServerToClient>>;
+
+// Result of a request: the ID of the message it stored, if any, or an error for the client
+type Outcome = Result<Option<u64>, RequestError>;
+
+/// Why a request failed, sent to the client as `ServerToClient::Error`.
+struct RequestError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl RequestError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        RequestError {
+            code,
+            message: message.into(),
+        }
+    }
 }
 
+// Optional features announced to clients in the handshake
+const CAPABILITIES: &[&str] = &[
+    capability::ROOMS,
+    capability::OFFLINE_DELIVERY,
+    capability::READ_RECEIPTS,
+    capability::TYPING,
+    capability::PRESENCE,
+    capability::EDITING,
+    capability::REACTIONS,
+    capability::THREADS,
+    capability::SEARCH,
+    capability::FILES,
+    capability::BLOCKING,
+];
+
+// Longest handle, in characters
+const MAX_HANDLE_LEN: usize = 32;
+// Longest status text a user can set, in characters
+const MAX_STATUS_LEN: usize = 100;
+// Longest message, in bytes
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+// Most a response bundling messages may hold, measured as JSON which is the largest
+// encoding. Well below the frame size clients accept by default.
+const MAX_BATCH_SIZE: usize = 1024 * 1024;
+// Messages returned by `GetMessages` without a limit, and the most it returns at once
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+// Most messages a search returns
+const MAX_SEARCH_HITS: usize = 50;
+// Longest reaction, in characters. Enough for emoji made of several code points.
+const MAX_REACTION_LEN: usize = 8;
+// Longest file name, in bytes
+const MAX_FILE_NAME_LEN: usize = 255;
+// Files a user may be uploading at once
+const MAX_OPEN_UPLOADS: usize = 8;
+// Uploaded files of a user kept for download at once, beyond that the oldest is dropped
+const MAX_WAITING_FILES: usize = 16;
+// How long an offered file is kept for recipients that haven't downloaded it
+const TRANSFER_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Messages that may be queued for a client before it counts as too slow
+const OUTBOUND_CAPACITY: usize = 256;
+// Requests are small, the limit only needs room for the largest legitimate one
+const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+// How long a started frame or TLS handshake may take before the peer is dropped
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Clone, Debug)]
-struct Chat {
-    messages: Vec<Message>,
+struct Client {
+    tx: mpsc::Sender<ServerToClient>,
+    // Cancelled to drop the connection
+    shutdown: CancellationToken,
+}
+
+impl Client {
+    /// Queue a message for the client without blocking. A client that can't keep
+    /// up with its queue is disconnected rather than stalling the sender.
+    fn send(&self, msg: ServerToClient) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(msg) {
+            if !self.shutdown.is_cancelled() {
+                eprintln!("Outbound queue full, disconnecting slow client");
+                self.shutdown.cancel();
+            }
+        }
+    }
 }
 
 struct ServerState {
     clients: HashMap<String, Client>,
-    chats: HashMap<(String, String), Chat>,
+    store: Box<dyn ChatStore>,
+    accounts: Accounts,
+    blocks: BlockLists,
+    // Every handle that has registered or chatted before, online or not
+    known_users: HashSet<String>,
+    // Messages waiting for their offline recipient to register again
+    pending: HashMap<String, Vec<Message>>,
+    rooms: Rooms,
+    // Presence and status of every user that has logged in
+    profiles: Profiles,
+    // Users that listed all users and follow everyone's presence since
+    presence_watchers: HashSet<String>,
+    // Files being uploaded or waiting to be downloaded, by transfer ID
+    transfers: HashMap<u64, Transfer>,
+    next_transfer_id: u64,
+}
+
+/// A file offered in a chat. Only kept in memory, until every recipient downloaded
+/// it or it expires.
+struct Transfer {
+    offer: FileOffer,
+    offered: Instant,
+    // The chat as the uploader named it, and as the recipients know it
+    target: String,
+    recipient_target: String,
+    // Recipients that haven't downloaded the file yet
+    waiting: HashSet<String>,
+    // Data received so far, and its running checksum
+    data: Arc<Vec<u8>>,
+    hasher: Sha256,
 }
 
-fn normalize_key(s1: &str, s2: &str) -> (String, String) {
+impl Transfer {
+    fn uploaded(&self) -> bool {
+        self.data.len() as u64 == self.offer.size
+    }
+}
+
+impl ServerState {
+    fn user_info(&self, handle: &str) -> UserInfo {
+        let default = Profile::default();
+        let profile = self.profiles.get(handle).unwrap_or(&default);
+
+        UserInfo {
+            handle: handle.to_string(),
+            presence: profile.presence,
+            status: profile.status.clone(),
+            last_seen: profile.last_seen,
+        }
+    }
+
+    /// Drop the transfers offered longer than `TRANSFER_EXPIRY` ago, downloaded or not.
+    fn expire_transfers(&mut self) {
+        self.transfers
+            .retain(|_, transfer| transfer.offered.elapsed() < TRANSFER_EXPIRY);
+    }
+
+    /// Tell the user itself and everyone interested about a change in presence:
+    /// those sharing a chat or room with the user and those that listed all users.
+    /// Users that blocked `handle` aren't told.
+    fn broadcast_presence(&self, handle: &str) {
+        let mut interested = self.store.partners(handle);
+        interested.extend(self.rooms.co_members(handle).cloned());
+        interested.extend(self.presence_watchers.iter().cloned());
+        interested.insert(handle.to_string());
+
+        let user = self.user_info(handle);
+        for other in interested
+            .iter()
+            .filter(|other| !self.blocks.is_blocked(other, handle))
+        {
+            if let Some(client) = self.clients.get(other) {
+                client.send(ServerToClient::PresenceChanged { user: user.clone() });
+            }
+        }
+    }
+}
+
+struct Config {
+    address: String,
+    data_dir: Option<PathBuf>,
+    // Certificate chain and private key, TLS is enabled when both are given
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    max_frame_size: usize,
+}
+
+fn parse_args() -> io::Result<Config> {
+    let mut config = Config {
+        address: "0.0.0.0:8080".to_string(),
+        data_dir: None,
+        cert: None,
+        key: None,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-dir" => {
+                let dir = args.next().ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--data-dir requires a directory",
+                ))?;
+                config.data_dir = Some(PathBuf::from(dir));
+            }
+            "--cert" => {
+                let cert = args.next().ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--cert requires a PEM file",
+                ))?;
+                config.cert = Some(PathBuf::from(cert));
+            }
+            "--key" => {
+                let key = args.next().ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--key requires a PEM file",
+                ))?;
+                config.key = Some(PathBuf::from(key));
+            }
+            "--max-frame-size" => {
+                config.max_frame_size =
+                    args.next()
+                        .and_then(|size| size.parse().ok())
+                        .ok_or(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "--max-frame-size requires a number of bytes",
+                        ))?;
+            }
+            _ => config.address = arg,
+        }
+    }
+
+    if config.cert.is_some() != config.key.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--cert and --key have to be given together",
+        ));
+    }
+
+    Ok(config)
+}
+
+fn normalize_key(s1: &str, s2: &str) -> ChatKey {
     let mut pair = [s1.to_string(), s2.to_string()];
     pair.sort();
-    (pair[0].clone(), pair[1].clone())
+    let [first, second] = pair;
+    ChatKey::Direct(first, second)
+}
+
+fn valid_room_name(room: &str) -> bool {
+    is_room(room) && room.len() > 1 && !room.contains(char::is_whitespace)
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let config = parse_args()?;
 
-    let server = {
-        if args.len() < 2 {
-            "0.0.0.0:8080"
-        } else {
-            args[1].as_str()
+    let store: Box<dyn ChatStore> = match &config.data_dir {
+        Some(dir) => {
+            println!("Persisting chat history in:\t{}", dir.display());
+            Box::new(LogStore::open(dir)?)
         }
+        None => Box::new(MemoryStore::default()),
     };
-    let listener = TcpListener::bind(server);
 
-    println!("Chat Server listening on:\t{}", server);
+    let accounts = Accounts::open(config.data_dir.as_deref())?;
+    let blocks = BlockLists::open(config.data_dir.as_deref())?;
+    let profiles = Profiles::open(config.data_dir.as_deref())?;
+    // Rooms from before memberships were stored are only known from their messages
+    let mut rooms = Rooms::open(config.data_dir.as_deref())?;
+    rooms.restore(store.rooms());
+
+    let tls_config = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => {
+            println!("TLS enabled with certificate:\t{}", cert.display());
+            Some(transport::server_config(cert, key)?)
+        }
+        _ => None,
+    };
+
+    let tls_acceptor = tls_config.map(TlsAcceptor::from);
+
+    let listener = TcpListener::bind(&config.address).await?;
+
+    println!("Chat Server listening on:\t{}", config.address);
 
     let server_state = Arc::new(Mutex::new(ServerState {
         clients: HashMap::new(),
-        chats: HashMap::new(),
+        known_users: store
+            .participants()
+            .into_iter()
+            .chain(accounts.handles().cloned())
+            .collect(),
+        rooms,
+        store,
+        accounts,
+        blocks,
+        pending: HashMap::new(),
+        profiles,
+        presence_watchers: HashSet::new(),
+        transfers: HashMap::new(),
+        next_transfer_id: 0,
     }));
 
-    for stream in listener?.incoming() {
-        let stream = stream?;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Running out of file descriptors shouldn't take the whole server down
+                eprintln!("Error accepting connection:\t{}", e);
+                continue;
+            }
+        };
 
-        println!(
-            "Incomming connection from:\t{}\n",
-            stream.peer_addr().unwrap()
-        );
+        println!("Incomming connection from:\t{}\n", addr);
 
-        //let stream_clone = stream.try_clone()?;
         let state_clone = server_state.clone();
+        let tls_clone = tls_acceptor.clone();
+        let max_frame_size = config.max_frame_size;
+
+        tokio::spawn(async move {
+            let result = match tls_clone {
+                Some(acceptor) => match time::timeout(FRAME_TIMEOUT, acceptor.accept(stream)).await
+                {
+                    Ok(Ok(stream)) => handle_client(stream, state_clone, max_frame_size).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "TLS handshake timed out",
+                    )),
+                },
+                None => handle_client(stream, state_clone, max_frame_size).await,
+            };
 
-        thread::spawn(move || {
-            if let Err(e) = handle_client(stream, state_clone) {
+            if let Err(e) = result {
                 eprintln!("Error handling client:\t{}", e);
             }
         });
     }
+}
 
+fn check_message_len(content: &str) -> Result<(), RequestError> {
+    if content.len() > MAX_MESSAGE_LEN {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            format!("Messages can't be longer than {} bytes.", MAX_MESSAGE_LEN),
+        ));
+    }
     Ok(())
 }
+
+/// How many of `messages`, taken in order, fit in one response. At least one, so a
+/// batch always makes progress.
+fn batch_len<'a>(messages: impl Iterator<Item = &'a Message>) -> usize {
+    let mut size = 0;
+    let mut count = 0;
+    for message in messages {
+        size += serde_json::to_vec(message).map_or(0, |data| data.len());
+        if size > MAX_BATCH_SIZE && count > 0 {
+            break;
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Store a message and deliver it to `target`. Returns the ID of the stored message.
 fn send_chat_message(
     state: &Arc<Mutex<ServerState>>,
-    handle: &String,
-    target: &String,
-    content: &String,
-) -> io::Result<()> {
+    handle: &str,
+    target: &str,
+    content: &str,
+    parent: Option<u64>,
+) -> Result<u64, RequestError> {
+    check_message_len(content)?;
+
+    if is_room(target) {
+        return send_room_message(state, handle, target, content, parent);
+    }
+
     let mut server_state = state.lock().unwrap();
 
-    if !server_state.clients.contains_key(target) {
-        // The target handle isn't connected
-        let mut client_stream = &server_state.clients.get_mut(handle).unwrap().stream;
-        send_msg(
-            &mut client_stream,
-            &ServerToClient::Error {
-                message: "Target handle doesn't exist.".to_string(),
-            },
-        )?;
-        return Ok(());
+    if !server_state.known_users.contains(target) {
+        // The target handle has never been registered
+        return Err(RequestError::new(
+            ErrorCode::UnknownUser,
+            "Target handle doesn't exist.",
+        ));
     }
 
-    let lookup_key = normalize_key(&handle, &target);
-    if !server_state.chats.contains_key(&lookup_key) {
-        server_state.chats.insert(
-            lookup_key.clone(),
-            Chat {
-                messages: Vec::<Message>::new(),
-            },
-        );
+    let lookup_key = normalize_key(handle, target);
+    let parent = thread_root(&server_state, &lookup_key, parent)?;
+    let message = server_state
+        .store
+        .append(&lookup_key, handle, content, parent)
+        .map_err(store_error)?;
+    let id = message.id;
+
+    // The sender keeps it in their history, but a recipient who blocked them never sees it
+    if server_state.blocks.is_blocked(target, handle) {
+        return Ok(id);
     }
 
-    let chat = server_state.chats.get_mut(&lookup_key).unwrap();
+    match server_state.clients.get(target) {
+        Some(client) => {
+            // Send the message to the target client
+            client.send(ServerToClient::ChatMessage {
+                message,
+                room: None,
+            });
+        }
+        None => {
+            // The target is offline, deliver it once they register again
+            server_state
+                .pending
+                .entry(target.to_string())
+                .or_default()
+                .push(message);
+        }
+    }
 
-    chat.messages.push(Message {
-        sender: handle.clone(),
-        content: content.clone(),
+    Ok(id)
+}
+
+fn send_room_message(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    room: &str,
+    content: &str,
+    parent: Option<u64>,
+) -> Result<u64, RequestError> {
+    let mut server_state = state.lock().unwrap();
+
+    let members = match server_state.rooms.members(room) {
+        Some(members) if members.contains(handle) => members.clone(),
+        _ => {
+            return Err(RequestError::new(
+                ErrorCode::NotAMember,
+                format!("You are not a member of {}.", room),
+            ))
+        }
+    };
+
+    let key = ChatKey::Room(room.to_string());
+    let parent = thread_root(&server_state, &key, parent)?;
+    let message = server_state
+        .store
+        .append(&key, handle, content, parent)
+        .map_err(store_error)?;
+
+    // Fan the message out to every connected member except the sender and those blocking them
+    for member in members
+        .iter()
+        .filter(|m| m.as_str() != handle && !server_state.blocks.is_blocked(m, handle))
+    {
+        if let Some(client) = server_state.clients.get(member) {
+            client.send(ServerToClient::ChatMessage {
+                message: message.clone(),
+                room: Some(room.to_string()),
+            });
+        }
+    }
+
+    Ok(message.id)
+}
+
+/// The thread a reply to `parent` belongs to, named by its first message.
+fn thread_root(
+    server_state: &ServerState,
+    key: &ChatKey,
+    parent: Option<u64>,
+) -> Result<Option<u64>, RequestError> {
+    let Some(parent) = parent else {
+        return Ok(None);
+    };
+
+    match server_state.store.message(key, parent) {
+        Some(message) => Ok(Some(message.parent.unwrap_or(message.id))),
+        None => Err(unknown_message()),
+    }
+}
+
+/// Storage failures are logged, the client only learns that its request was lost.
+fn store_error(e: io::Error) -> RequestError {
+    eprintln!("Error writing to the chat store:\t{}", e);
+    RequestError::new(
+        ErrorCode::Internal,
+        "The server couldn't store the request.",
+    )
+}
+
+fn create_room(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    room: String,
+) -> Result<(), RequestError> {
+    let mut server_state = state.lock().unwrap();
+
+    if !valid_room_name(&room) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRoomName,
+            "Room names must start with '#' and contain no whitespace.",
+        ));
+    }
+
+    if server_state.rooms.exists(&room) {
+        return Err(RequestError::new(
+            ErrorCode::RoomExists,
+            format!("Room {} already exists.", room),
+        ));
+    }
+
+    // The creator automatically joins the new room
+    server_state
+        .rooms
+        .join(&room, handle)
+        .map_err(store_error)?;
+
+    client.send(ServerToClient::ChatMessages {
+        partner: room,
+        messages: Vec::new(),
+        partner_read: None,
+        has_more: false,
     });
 
-    // Send the message to the target client
-    let mut target_stream = &server_state.clients.get_mut(target).unwrap().stream;
+    Ok(())
+}
 
-    let _ = send_msg(
-        &mut target_stream,
-        &ServerToClient::ChatMessage {
-            sender: handle.clone(),
-            content: content.clone(),
-        },
+fn join_room(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    room: String,
+) -> Result<(), RequestError> {
+    let mut server_state = state.lock().unwrap();
+
+    if !server_state.rooms.exists(&room) {
+        return Err(RequestError::new(
+            ErrorCode::UnknownRoom,
+            format!("Room {} doesn't exist.", room),
+        ));
+    }
+    server_state
+        .rooms
+        .join(&room, handle)
+        .map_err(store_error)?;
+
+    let (mut messages, mut has_more) = server_state.store.page(
+        &ChatKey::Room(room.clone()),
+        None,
+        None,
+        DEFAULT_PAGE_SIZE,
+        &|message| !server_state.blocks.is_blocked(handle, &message.sender),
     );
+    let fit = batch_len(messages.iter().rev());
+    has_more |= fit < messages.len();
+    messages.drain(..messages.len() - fit);
+
+    client.send(ServerToClient::ChatMessages {
+        partner: room,
+        messages,
+        partner_read: None,
+        has_more,
+    });
+
+    Ok(())
+}
+
+fn leave_room(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    room: String,
+) -> Result<(), RequestError> {
+    let mut server_state = state.lock().unwrap();
+
+    let was_member = server_state
+        .rooms
+        .leave(&room, handle)
+        .map_err(store_error)?;
+
+    if !was_member {
+        return Err(RequestError::new(
+            ErrorCode::NotAMember,
+            format!("You are not a member of {}.", room),
+        ));
+    }
+
+    client.send(ServerToClient::LeftRoom { room });
 
     Ok(())
 }
 
-fn handle_client(mut stream: TcpStream, state: Arc<Mutex<ServerState>>) -> io::Result<()> {
-    // First message should be the client registring with a handle
-    let data =
-        recv_msg(&mut stream)?.ok_or(io::Error::new(io::ErrorKind::ConnectionReset, "No data"))?;
-    let msg: ClientToServer = decode(&data)?;
+async fn handle_client<S>(
+    mut stream: S,
+    state: Arc<Mutex<ServerState>>,
+    max_frame_size: usize,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (codec, client_capabilities) = match handshake(&mut stream, max_frame_size).await? {
+        Some(greeting) => greeting,
+        None => return Ok(()),
+    };
+
+    let (reader, writer) = tokio::io::split(stream);
+    let mut frames = FramedRead::new(
+        FrameTimer::new(reader),
+        MessageCodec::with_max_frame_size(codec, max_frame_size),
+    );
+
+    // Everything sent to this client goes through its queue and a dedicated writer task,
+    // so nobody ever blocks on this client's socket
+    let (tx, rx) = mpsc::channel(OUTBOUND_CAPACITY);
+    let client = Client {
+        tx,
+        shutdown: CancellationToken::new(),
+    };
+    tokio::spawn(write_messages(
+        FramedWrite::new(writer, MessageCodec::new(codec)),
+        rx,
+        client.shutdown.clone(),
+    ));
 
-    let handle = if let ClientToServer::Register { handle } = msg {
+    // The client has to log in or create an account before anything else
+    let handle = match authenticate(&mut frames, &client, &state).await? {
+        Some(handle) => handle,
+        None => return Ok(()),
+    };
+
+    {
         let mut server_state = state.lock().unwrap();
-        if server_state.clients.contains_key(&handle) {
-            let _ = send_msg(
-                &mut stream,
-                &ServerToClient::Error {
-                    message: "Handle already taken".to_string(),
-                },
-            );
-            return Ok(());
+        if let Some(mut messages) = server_state.pending.remove(&handle) {
+            // Senders may have been blocked after the messages were queued
+            messages.retain(|m| !server_state.blocks.is_blocked(&handle, &m.sender));
+            // Clients that can't show them still find the messages in the chat history
+            if client_capabilities
+                .iter()
+                .any(|c| c == capability::OFFLINE_DELIVERY)
+            {
+                while !messages.is_empty() {
+                    let rest = messages.split_off(batch_len(messages.iter()));
+                    client.send(ServerToClient::DelayedMessages { messages });
+                    messages = rest;
+                }
+            }
         }
-        server_state.clients.insert(
-            handle.clone(),
-            Client {
-                stream: stream.try_clone()?,
-            },
-        );
 
-        send_msg(
-            &mut stream,
-            &ServerToClient::Registered {
-                handle: handle.clone(),
-            },
-        )?;
-        handle
-    } else {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Expected Register Command.",
-        ));
+        // Files offered while the user was offline
+        server_state.expire_transfers();
+        for transfer in server_state.transfers.values() {
+            if transfer.uploaded() && transfer.waiting.contains(&handle) {
+                client.send(ServerToClient::FileOffered {
+                    target: transfer.recipient_target.clone(),
+                    offer: transfer.offer.clone(),
+                });
+            }
+        }
+    }
+
+    let result = serve_client(&mut frames, &client, &state, &handle).await;
+
+    // However the session ended (EOF, I/O error or an undecodable message) the handle is released.
+    // Dropping the last sender lets the writer task flush what is queued and close the socket.
+    disconnect_client(&state, &handle);
+
+    result
+}
+
+/// Exchange `Hello` and `Welcome` before anything else is sent. Returns the codec
+/// picked by the client and its capabilities, or `None` if the client was
+/// rejected or went away.
+async fn handshake<S>(
+    stream: &mut S,
+    max_frame_size: usize,
+) -> io::Result<Option<(Codec, Vec<String>)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let data = match time::timeout(FRAME_TIMEOUT, read_frame(stream, max_frame_size)).await {
+        Ok(data) => data?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client didn't say hello in time",
+            ))
+        }
     };
 
+    let Some(data) = data else {
+        return Ok(None);
+    };
+
+    let (reply, greeting) = match Codec::Json.decode(&data) {
+        Ok(ClientToServer::Hello {
+            protocol_version,
+            codec,
+            capabilities,
+        }) => {
+            if protocol_version != PROTOCOL_VERSION {
+                let reason = format!(
+                    "Protocol version {} is not supported, the server speaks version {}.",
+                    protocol_version, PROTOCOL_VERSION
+                );
+                (ServerToClient::Rejected { reason }, None)
+            } else if let Some(codec) = Codec::from_name(&codec) {
+                let welcome = ServerToClient::Welcome {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                };
+                (welcome, Some((codec, capabilities)))
+            } else {
+                let supported: Vec<&str> = Codec::ALL.iter().map(|c| c.name()).collect();
+                let reason = format!(
+                    "Codec '{}' is not supported, the server supports: {}.",
+                    codec,
+                    supported.join(", ")
+                );
+                (ServerToClient::Rejected { reason }, None)
+            }
+        }
+        _ => {
+            let reason = "Expected a Hello handshake, the client is probably outdated.".to_string();
+            (ServerToClient::Rejected { reason }, None)
+        }
+    };
+
+    write_frame(stream, &Codec::Json.encode(&reply)?).await?;
+
+    Ok(greeting)
+}
+
+async fn write_messages<W>(
+    mut sink: ServerFrames<W>,
+    mut rx: mpsc::Receiver<ServerToClient>,
+    shutdown: CancellationToken,
+) where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => msg,
+            _ = shutdown.cancelled() => break,
+        };
+
+        let Some(msg) = msg else {
+            break;
+        };
+
+        if let Err(e) = sink.send(msg).await {
+            eprintln!("Error writing to client:\t{}", e);
+            // Make the reading side give up on the connection as well
+            shutdown.cancel();
+            break;
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+/// Remembers when the first bytes of the frame being received arrived, so a
+/// frame can be timed out however slowly its bytes trickle in.
+struct FrameTimer<R> {
+    inner: R,
+    started: Option<Instant>,
+}
+
+impl<R> FrameTimer<R> {
+    fn new(inner: R) -> Self {
+        FrameTimer {
+            inner,
+            started: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FrameTimer<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled && self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        result
+    }
+}
+
+/// Wait for the next message from the client. Returns `None` when the client
+/// disconnected or the connection was shut down from the server side.
+///
+/// Idle clients may wait as long as they like, but a frame has to be completed
+/// within `FRAME_TIMEOUT` of its first bytes arriving so trickling in partial
+/// frames can't tie up the connection forever.
+async fn next_message<R>(
+    frames: &mut ClientFrames<R>,
+    client: &Client,
+) -> io::Result<Option<Request>>
+where
+    R: AsyncRead + Unpin,
+{
     loop {
-        let data = match recv_msg(&mut stream)? {
-            Some(d) => d,
-            None => break,
+        // Without a started frame just look again after a while
+        let started = frames.get_ref().started;
+        let deadline = started.unwrap_or_else(Instant::now) + FRAME_TIMEOUT;
+
+        let frame = tokio::select! {
+            frame = frames.next() => frame,
+            _ = client.shutdown.cancelled() => None,
+            _ = time::sleep_until(deadline) => {
+                if started.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "client didn't complete its frame in time",
+                    ));
+                }
+                continue;
+            }
         };
 
-        let msg: ClientToServer = decode(&data)?;
+        // Bytes left over belong to the next frame, which started arriving just now
+        let pending = !frames.read_buffer().is_empty();
+        frames.get_mut().started = pending.then(Instant::now);
+
+        return frame.transpose();
+    }
+}
 
-        match msg {
-            ClientToServer::Register { handle: _ } => {
-                // Should never happen
-                todo!();
+/// Handle `CreateAccount` and `Login` requests until one succeeds. Returns the
+/// logged in handle, or `None` if the client disconnected before that.
+async fn authenticate<R>(
+    frames: &mut ClientFrames<R>,
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+) -> io::Result<Option<String>>
+where
+    R: AsyncRead + Unpin,
+{
+    while let Some(Request { id, body }) = next_message(frames, client).await? {
+        // Hashing is slow, so it runs on the blocking pool without holding the lock
+        let result = match body {
+            ClientToServer::CreateAccount { handle, password } => {
+                let state = state.clone();
+                tokio::task::spawn_blocking(move || {
+                    create_account(&state, &handle, &password).map(|_| handle)
+                })
+                .await
+                .map_err(io::Error::other)?
             }
+            ClientToServer::Login { handle, password } => {
+                let credentials = state.lock().unwrap().accounts.credentials(&handle);
+                let verified = tokio::task::spawn_blocking(move || match credentials {
+                    Some(credentials) => credentials.verify(&password),
+                    None => {
+                        Credentials::dummy().verify(&password);
+                        false
+                    }
+                })
+                .await
+                .map_err(io::Error::other)?;
+
+                if verified {
+                    Ok(handle)
+                } else {
+                    Err(RequestError::new(
+                        ErrorCode::Unauthorized,
+                        "Unknown user or wrong password.",
+                    ))
+                }
+            }
+            _ => Err(RequestError::new(
+                ErrorCode::NotRegistered,
+                "Please log in first.",
+            )),
+        };
+
+        let handle = match result {
+            Ok(handle) => handle,
+            Err(error) => {
+                respond(client, id, Err(error));
+                continue;
+            }
+        };
+
+        let mut server_state = state.lock().unwrap();
+        if server_state.clients.contains_key(&handle) {
+            respond(
+                client,
+                id,
+                Err(RequestError::new(
+                    ErrorCode::AlreadyLoggedIn,
+                    "User is already logged in.",
+                )),
+            );
+            continue;
+        }
+
+        server_state.clients.insert(handle.clone(), client.clone());
+
+        client.send(ServerToClient::LoggedIn {
+            handle: handle.clone(),
+        });
+        respond(client, id, Ok(None));
+
+        server_state
+            .profiles
+            .set_presence(&handle, Presence::Online);
+        server_state.broadcast_presence(&handle);
+
+        return Ok(Some(handle));
+    }
+
+    Ok(None)
+}
+
+fn create_account(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    password: &str,
+) -> Result<(), RequestError> {
+    if handle.is_empty() || handle.contains(char::is_whitespace) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidHandle,
+            "Handles can't be empty or contain whitespace.",
+        ));
+    }
+    if is_room(handle) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidHandle,
+            "Handles can't start with '#'.",
+        ));
+    }
+    if handle.chars().count() > MAX_HANDLE_LEN {
+        return Err(RequestError::new(
+            ErrorCode::InvalidHandle,
+            format!(
+                "Handles can't be longer than {} characters.",
+                MAX_HANDLE_LEN
+            ),
+        ));
+    }
+    // Messages from it would pass for notices of the client
+    if handle.eq_ignore_ascii_case(SYSTEM_SENDER) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidHandle,
+            "That handle is reserved.",
+        ));
+    }
+    if password.is_empty() {
+        return Err(RequestError::new(
+            ErrorCode::InvalidPassword,
+            "Password can't be empty.",
+        ));
+    }
+    if state.lock().unwrap().accounts.exists(handle) {
+        return Err(RequestError::new(
+            ErrorCode::HandleTaken,
+            "Handle already taken.",
+        ));
+    }
+
+    let credentials = Credentials::new(password)
+        .map_err(|e| RequestError::new(ErrorCode::Internal, e.to_string()))?;
+
+    let mut server_state = state.lock().unwrap();
+    // Someone may have claimed the handle while the password was being hashed
+    if server_state.accounts.exists(handle) {
+        return Err(RequestError::new(
+            ErrorCode::HandleTaken,
+            "Handle already taken.",
+        ));
+    }
+    server_state
+        .accounts
+        .insert(handle, credentials)
+        .map_err(|e| RequestError::new(ErrorCode::Internal, e.to_string()))?;
+    server_state.known_users.insert(handle.to_string());
+
+    Ok(())
+}
+
+fn disconnect_client(state: &Arc<Mutex<ServerState>>, handle: &str) {
+    let mut server_state = state.lock().unwrap();
+    server_state.clients.remove(handle);
+    server_state.presence_watchers.remove(handle);
+
+    // Nobody can finish the user's uploads anymore
+    server_state
+        .transfers
+        .retain(|_, transfer| transfer.offer.sender != handle || transfer.uploaded());
+
+    println!("Client disconnected:\t{}\n", handle);
+
+    if let Err(e) = server_state.profiles.set_offline(handle, unix_time()) {
+        eprintln!("Error storing when {} was last seen:\t{}", handle, e);
+    }
+
+    server_state.broadcast_presence(handle);
+}
+
+fn set_presence(state: &Arc<Mutex<ServerState>>, handle: &str, presence: Presence) -> Outcome {
+    if presence == Presence::Offline {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "Offline is set by disconnecting.",
+        ));
+    }
+
+    let mut server_state = state.lock().unwrap();
+    server_state.profiles.set_presence(handle, presence);
+    server_state.broadcast_presence(handle);
+
+    Ok(None)
+}
+
+fn set_status(state: &Arc<Mutex<ServerState>>, handle: &str, status: Option<String>) -> Outcome {
+    let status = status
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    if status
+        .as_ref()
+        .is_some_and(|s| s.chars().count() > MAX_STATUS_LEN)
+    {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            format!("Status can't be longer than {} characters.", MAX_STATUS_LEN),
+        ));
+    }
+
+    let mut server_state = state.lock().unwrap();
+    server_state
+        .profiles
+        .set_status(handle, status)
+        .map_err(store_error)?;
+    server_state.broadcast_presence(handle);
+
+    Ok(None)
+}
+
+/// Answer a request with an `Ack` on success or an `Error` on failure. Successful
+/// requests without an ID get no answer beyond their regular response.
+fn respond(client: &Client, request_id: Option<u64>, outcome: Outcome) {
+    match (outcome, request_id) {
+        (Ok(message_id), Some(request_id)) => client.send(ServerToClient::Ack {
+            request_id,
+            message_id,
+        }),
+        (Ok(_), None) => {}
+        (Err(error), request_id) => client.send(ServerToClient::Error {
+            request_id,
+            code: error.code,
+            message: error.message,
+        }),
+    }
+}
+
+async fn serve_client<R>(
+    frames: &mut ClientFrames<R>,
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    while let Some(Request { id, body }) = next_message(frames, client).await? {
+        let outcome = match body {
+            ClientToServer::Hello { .. }
+            | ClientToServer::CreateAccount { .. }
+            | ClientToServer::Login { .. } => Err(RequestError::new(
+                ErrorCode::AlreadyLoggedIn,
+                "Already logged in.",
+            )),
             ClientToServer::ListUsers => {
-                let server_state = state.lock().unwrap();
-                let users: Vec<String> = server_state.clients.keys().cloned().collect();
-                let _ = send_msg(&mut stream, &ServerToClient::UserList { users })?;
+                let mut server_state = state.lock().unwrap();
+                server_state.presence_watchers.insert(handle.to_string());
+                let mut users: Vec<UserInfo> = server_state
+                    .known_users
+                    .iter()
+                    .filter(|user| !server_state.blocks.is_blocked(handle, user))
+                    .map(|user| server_state.user_info(user))
+                    .collect();
+                users.sort_by(|a, b| a.handle.cmp(&b.handle));
+                client.send(ServerToClient::UserList { users });
+                Ok(None)
             }
-            ClientToServer::SendMessage { content, target } => {
+            ClientToServer::SendMessage {
+                content,
+                target,
+                parent,
+            } => {
                 println!(
                     "Received send message request from {}, '{}' to '{}'\n",
                     handle, content, target
                 );
-                let _ = send_chat_message(&state, &handle, &target, &content);
+                send_chat_message(state, handle, &target, &content, parent).map(Some)
+            }
+            ClientToServer::GetMessages {
+                target,
+                before,
+                after,
+                limit,
+            } => get_messages(client, state, handle, target, before, after, limit),
+            ClientToServer::CreateRoom { room } => {
+                create_room(client, state, handle, room).map(|_| None)
+            }
+            ClientToServer::JoinRoom { room } => {
+                join_room(client, state, handle, room).map(|_| None)
             }
-            ClientToServer::GetMessages { target } => {
+            ClientToServer::LeaveRoom { room } => {
+                leave_room(client, state, handle, room).map(|_| None)
+            }
+            ClientToServer::ListRooms => {
                 let server_state = state.lock().unwrap();
+                let rooms: Vec<String> = server_state.rooms.names().cloned().collect();
+                client.send(ServerToClient::RoomList { rooms });
+                Ok(None)
+            }
+            ClientToServer::MarkRead { target, message_id } => {
+                mark_read(state, handle, target, message_id)
+            }
+            ClientToServer::Typing { target, active } => {
+                relay_typing(state, handle, target, active)
+            }
+            ClientToServer::SetPresence { presence } => set_presence(state, handle, presence),
+            ClientToServer::SetStatus { status } => set_status(state, handle, status),
+            ClientToServer::EditMessage {
+                target,
+                message_id,
+                content,
+            } => edit_message(state, handle, target, message_id, content),
+            ClientToServer::DeleteMessage { target, message_id } => {
+                delete_message(state, handle, target, message_id)
+            }
+            ClientToServer::React {
+                target,
+                message_id,
+                emoji,
+                active,
+            } => react(state, handle, target, message_id, emoji, active),
+            ClientToServer::Search {
+                query,
+                conversation,
+            } => search(client, state, handle, query, conversation),
+            ClientToServer::OfferFile {
+                target,
+                name,
+                size,
+                sha256,
+            } => offer_file(state, handle, target, name, size, sha256),
+            ClientToServer::UploadChunk {
+                transfer_id,
+                offset,
+                data,
+            } => upload_chunk(state, handle, transfer_id, offset, data),
+            ClientToServer::AcceptFile { transfer_id } => {
+                accept_file(client, state, handle, transfer_id)
+            }
+            ClientToServer::Block {
+                handle: target,
+                active,
+            } => block(client, state, handle, target, active),
+            ClientToServer::ListBlocked => {
+                let server_state = state.lock().unwrap();
+                let handles = server_state.blocks.blocked(handle);
+                client.send(ServerToClient::BlockedList { handles });
+                Ok(None)
+            }
+        };
 
-                let lookup_key = normalize_key(&handle, &target);
-                let messages: Vec<Message> = match server_state.chats.get(&lookup_key) {
-                    Some(chat) => chat.messages.clone(),
-                    None => Vec::<Message>::new(),
-                };
+        respond(client, id, outcome);
+    }
+    Ok(())
+}
+
+fn get_messages(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    before: Option<u64>,
+    after: Option<u64>,
+    limit: Option<u32>,
+) -> Outcome {
+    let limit = limit.map_or(DEFAULT_PAGE_SIZE, |limit| limit as usize);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            format!("The limit has to be between 1 and {}.", MAX_PAGE_SIZE),
+        ));
+    }
+
+    let server_state = state.lock().unwrap();
+
+    let (lookup_key, partner_read) = if is_room(&target) {
+        if !server_state.rooms.is_member(&target, handle) {
+            return Err(RequestError::new(
+                ErrorCode::NotAMember,
+                format!("Join {} to read its messages.", target),
+            ));
+        }
+        (ChatKey::Room(target.clone()), None)
+    } else {
+        let key = normalize_key(handle, &target);
+        let partner_read = server_state.store.read_position(&key, &target);
+        (key, partner_read)
+    };
+    let (mut messages, mut has_more) =
+        server_state
+            .store
+            .page(&lookup_key, after, before, limit, &|message| {
+                !server_state.blocks.is_blocked(handle, &message.sender)
+            });
+
+    // Large messages make for a shorter page, keeping the end nearest the cursor
+    if after.is_some() && before.is_none() {
+        let fit = batch_len(messages.iter());
+        has_more |= fit < messages.len();
+        messages.truncate(fit);
+    } else {
+        let fit = batch_len(messages.iter().rev());
+        has_more |= fit < messages.len();
+        messages.drain(..messages.len() - fit);
+    }
 
-                send_msg(
-                    &mut stream,
-                    &ServerToClient::ChatMessages {
-                        partner: target,
-                        messages: messages,
-                    },
-                )?;
+    client.send(ServerToClient::ChatMessages {
+        partner: target,
+        messages,
+        partner_read,
+        has_more,
+    });
+
+    Ok(None)
+}
+
+/// The conversation `handle` refers to as `target`, the name the other
+/// participants know it by, and those participants.
+fn conversation(
+    server_state: &ServerState,
+    handle: &str,
+    target: &str,
+) -> Result<(ChatKey, String, HashSet<String>), RequestError> {
+    if is_room(target) {
+        match server_state.rooms.members(target) {
+            Some(members) if members.contains(handle) => {
+                let others = members.iter().filter(|m| *m != handle).cloned().collect();
+                Ok((
+                    ChatKey::Room(target.to_string()),
+                    target.to_string(),
+                    others,
+                ))
             }
+            _ => Err(RequestError::new(
+                ErrorCode::NotAMember,
+                format!("You are not a member of {}.", target),
+            )),
+        }
+    } else if server_state.known_users.contains(target) {
+        Ok((
+            normalize_key(handle, target),
+            handle.to_string(),
+            HashSet::from([target.to_string()]),
+        ))
+    } else {
+        Err(RequestError::new(
+            ErrorCode::UnknownUser,
+            "Target handle doesn't exist.",
+        ))
+    }
+}
+
+/// Move the read position of `handle` forward and tell the other participant of a
+/// direct chat. Receipts aren't sent for rooms, where every member would tell every other.
+fn mark_read(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    message_id: u64,
+) -> Outcome {
+    let mut server_state = state.lock().unwrap();
+
+    // Receipts name the conversation as the recipients see it
+    let (key, receipt_target, recipients) = conversation(&server_state, handle, &target)?;
+
+    let Some(position) = server_state
+        .store
+        .mark_read(&key, handle, message_id)
+        .map_err(store_error)?
+    else {
+        return Ok(None);
+    };
+    if is_room(&target) {
+        return Ok(None);
+    }
+
+    for recipient in recipients
+        .iter()
+        .filter(|r| !server_state.blocks.is_blocked(r, handle))
+    {
+        if let Some(client) = server_state.clients.get(recipient) {
+            client.send(ServerToClient::ReadReceipt {
+                target: receipt_target.clone(),
+                reader: handle.to_string(),
+                message_id: position,
+            });
+        }
+    }
+
+    Ok(None)
+}
+
+/// Relay a typing notification to the other participants that are online. Nothing is stored.
+fn relay_typing(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    active: bool,
+) -> Outcome {
+    let server_state = state.lock().unwrap();
+
+    let (_, typing_target, recipients) = conversation(&server_state, handle, &target)?;
+
+    for recipient in recipients
+        .iter()
+        .filter(|r| !server_state.blocks.is_blocked(r, handle))
+    {
+        if let Some(client) = server_state.clients.get(recipient) {
+            client.send(ServerToClient::Typing {
+                target: typing_target.clone(),
+                user: handle.to_string(),
+                active,
+            });
+        }
+    }
+
+    Ok(None)
+}
+
+fn unknown_message() -> RequestError {
+    RequestError::new(
+        ErrorCode::UnknownMessage,
+        "There is no message with that ID in the chat.",
+    )
+}
+
+/// Check that the message exists and was sent by `handle`.
+fn check_sender(
+    server_state: &ServerState,
+    handle: &str,
+    key: &ChatKey,
+    message_id: u64,
+) -> Result<(), RequestError> {
+    match server_state.store.message(key, message_id) {
+        Some(message) if message.sender == handle => Ok(()),
+        Some(_) => Err(RequestError::new(
+            ErrorCode::NotSender,
+            "You can only change your own messages.",
+        )),
+        None => Err(unknown_message()),
+    }
+}
+
+/// Replace the content of a message sent by `handle` and show the new version
+/// to everyone in the conversation that hasn't blocked them, including the editor.
+fn edit_message(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    message_id: u64,
+    content: String,
+) -> Outcome {
+    if content.trim().is_empty() {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "A message can't be empty, delete it instead.",
+        ));
+    }
+    check_message_len(&content)?;
+
+    let mut server_state = state.lock().unwrap();
+
+    let (key, change_target, recipients) = conversation(&server_state, handle, &target)?;
+    check_sender(&server_state, handle, &key, message_id)?;
+
+    let message = server_state
+        .store
+        .edit(&key, message_id, &content)
+        .map_err(store_error)?
+        .ok_or_else(unknown_message)?;
+
+    // Offline recipients get the new version once they are back
+    for pending in server_state.pending.values_mut().flatten() {
+        if pending.id == message_id {
+            *pending = message.clone();
+        }
+    }
+
+    for recipient in recipients
+        .iter()
+        .filter(|r| !server_state.blocks.is_blocked(r, handle))
+    {
+        if let Some(client) = server_state.clients.get(recipient) {
+            client.send(ServerToClient::MessageEdited {
+                target: change_target.clone(),
+                message: message.clone(),
+            });
+        }
+    }
+    if let Some(client) = server_state.clients.get(handle) {
+        client.send(ServerToClient::MessageEdited { target, message });
+    }
+
+    Ok(None)
+}
+
+/// Remove a message sent by `handle` for everyone in the conversation. Those that
+/// blocked `handle` never saw it and aren't told.
+fn delete_message(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    message_id: u64,
+) -> Outcome {
+    let mut server_state = state.lock().unwrap();
+
+    let (key, change_target, recipients) = conversation(&server_state, handle, &target)?;
+    check_sender(&server_state, handle, &key, message_id)?;
+
+    if !server_state
+        .store
+        .delete(&key, message_id)
+        .map_err(store_error)?
+    {
+        return Err(unknown_message());
+    }
+
+    server_state.pending.retain(|_, pending| {
+        pending.retain(|m| m.id != message_id);
+        !pending.is_empty()
+    });
+
+    for recipient in recipients
+        .iter()
+        .filter(|r| !server_state.blocks.is_blocked(r, handle))
+    {
+        if let Some(client) = server_state.clients.get(recipient) {
+            client.send(ServerToClient::MessageDeleted {
+                target: change_target.clone(),
+                message_id,
+            });
         }
     }
+    if let Some(client) = server_state.clients.get(handle) {
+        client.send(ServerToClient::MessageDeleted { target, message_id });
+    }
+
+    Ok(None)
+}
+
+/// Add or take back a reaction of `handle` to a message and show the new
+/// reactions to everyone in the conversation that hasn't blocked them, including
+/// the reacting user.
+fn react(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    message_id: u64,
+    emoji: String,
+    active: bool,
+) -> Outcome {
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_REACTION_LEN
+        || emoji.chars().any(char::is_whitespace)
+    {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "A reaction has to be a single emoji.",
+        ));
+    }
+
+    let mut server_state = state.lock().unwrap();
+
+    let (key, change_target, recipients) = conversation(&server_state, handle, &target)?;
+
+    let message = server_state
+        .store
+        .react(&key, message_id, handle, &emoji, active)
+        .map_err(store_error)?
+        .ok_or_else(unknown_message)?;
+
+    for pending in server_state.pending.values_mut().flatten() {
+        if pending.id == message_id {
+            *pending = message.clone();
+        }
+    }
+
+    for recipient in recipients
+        .iter()
+        .filter(|r| !server_state.blocks.is_blocked(r, handle))
+    {
+        if let Some(client) = server_state.clients.get(recipient) {
+            client.send(ServerToClient::ReactionsChanged {
+                target: change_target.clone(),
+                message_id,
+                reactions: message.reactions.clone(),
+            });
+        }
+    }
+    if let Some(client) = server_state.clients.get(handle) {
+        client.send(ServerToClient::ReactionsChanged {
+            target,
+            message_id,
+            reactions: message.reactions,
+        });
+    }
+
+    Ok(None)
+}
+
+/// Search the conversations of `handle`, or only the one with `conversation_target`.
+fn search(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    query: String,
+    conversation_target: Option<String>,
+) -> Outcome {
+    if search::words(&query).next().is_none() {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "Search for at least one word.",
+        ));
+    }
+
+    let server_state = state.lock().unwrap();
+
+    let only = match &conversation_target {
+        Some(target) => Some(conversation(&server_state, handle, target)?.0),
+        None => None,
+    };
+    let include = |key: &ChatKey, message: &Message| {
+        let in_chat = match (&only, key) {
+            (Some(only), key) => key == only,
+            (None, ChatKey::Direct(a, b)) => a == handle || b == handle,
+            (None, ChatKey::Room(room)) => server_state.rooms.is_member(room, handle),
+        };
+        in_chat && !server_state.blocks.is_blocked(handle, &message.sender)
+    };
+
+    let mut hits = server_state.store.search(&query, &include, MAX_SEARCH_HITS);
+    hits.truncate(batch_len(hits.iter().map(|(_, message)| message)));
+
+    let hits = hits
+        .into_iter()
+        .map(|(key, message)| SearchHit {
+            // Name the chat like the user would as a target
+            conversation: match key {
+                ChatKey::Direct(a, b) if a == handle => b,
+                ChatKey::Direct(a, _) => a,
+                ChatKey::Room(room) => room,
+            },
+            message,
+        })
+        .collect();
+
+    client.send(ServerToClient::SearchResults { query, hits });
+
+    Ok(None)
+}
+
+fn unknown_transfer() -> RequestError {
+    RequestError::new(
+        ErrorCode::UnknownTransfer,
+        "There is no file transfer with that ID for you, it may have expired.",
+    )
+}
+
+/// Start the upload of a file to the chat with `target`. Returns the ID of the transfer.
+fn offer_file(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    name: String,
+    size: u64,
+    sha256: String,
+) -> Outcome {
+    if name.is_empty()
+        || name.len() > MAX_FILE_NAME_LEN
+        || name.contains(['/', '\\'])
+        || name == "."
+        || name == ".."
+    {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "Not a valid file name, it can't contain directories.",
+        ));
+    }
+    if size > MAX_FILE_SIZE {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            format!("Files can't be larger than {} bytes.", MAX_FILE_SIZE),
+        ));
+    }
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "The checksum has to be a hex encoded SHA-256.",
+        ));
+    }
+
+    let mut server_state = state.lock().unwrap();
+
+    let (_, recipient_target, mut recipients) = conversation(&server_state, handle, &target)?;
+    recipients.retain(|recipient| !server_state.blocks.is_blocked(recipient, handle));
+
+    server_state.expire_transfers();
+
+    let uploading = server_state
+        .transfers
+        .values()
+        .filter(|transfer| transfer.offer.sender == handle && !transfer.uploaded())
+        .count();
+    if uploading >= MAX_OPEN_UPLOADS {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "Too many of your files are still uploading, wait for one to finish.",
+        ));
+    }
+
+    // Make room for this one by dropping the oldest file still waiting to be downloaded
+    let mut waiting: Vec<u64> = server_state
+        .transfers
+        .iter()
+        .filter(|(_, transfer)| transfer.offer.sender == handle && transfer.uploaded())
+        .map(|(&transfer_id, _)| transfer_id)
+        .collect();
+    if waiting.len() >= MAX_WAITING_FILES {
+        waiting.sort_unstable();
+        for transfer_id in &waiting[..=waiting.len() - MAX_WAITING_FILES] {
+            server_state.transfers.remove(transfer_id);
+        }
+    }
+
+    let transfer_id = server_state.next_transfer_id;
+    server_state.next_transfer_id += 1;
+    server_state.transfers.insert(
+        transfer_id,
+        Transfer {
+            offer: FileOffer {
+                transfer_id,
+                sender: handle.to_string(),
+                name,
+                size,
+                sha256: sha256.to_lowercase(),
+            },
+            target,
+            recipient_target,
+            waiting: recipients,
+            offered: Instant::now(),
+            data: Arc::new(Vec::new()),
+            hasher: Sha256::new(),
+        },
+    );
+
+    // Nothing to wait for with an empty file
+    if size == 0 {
+        finish_upload(&mut server_state, transfer_id)?;
+    }
+
+    Ok(Some(transfer_id))
+}
+
+/// Add the next chunk to a file `handle` is uploading, and offer the file once it's complete.
+fn upload_chunk(
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    transfer_id: u64,
+    offset: u64,
+    data: Vec<u8>,
+) -> Outcome {
+    let mut server_state = state.lock().unwrap();
+
+    let transfer = match server_state.transfers.get_mut(&transfer_id) {
+        Some(transfer) if transfer.offer.sender == handle && !transfer.uploaded() => transfer,
+        _ => return Err(unknown_transfer()),
+    };
+
+    let received = transfer.data.len() as u64;
+    if offset != received
+        || data.len() > FILE_CHUNK_SIZE
+        || received + data.len() as u64 > transfer.offer.size
+    {
+        server_state.transfers.remove(&transfer_id);
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "The chunk doesn't continue the file, the upload was cancelled.",
+        ));
+    }
+
+    transfer.hasher.update(&data);
+    Arc::make_mut(&mut transfer.data).extend_from_slice(&data);
+
+    if transfer.uploaded() {
+        finish_upload(&mut server_state, transfer_id)?;
+    }
+
+    Ok(None)
+}
+
+/// Verify a completely uploaded file and offer it to the chat, including the uploader.
+fn finish_upload(server_state: &mut ServerState, transfer_id: u64) -> Result<(), RequestError> {
+    let transfer = &server_state.transfers[&transfer_id];
+
+    if hex::encode(transfer.hasher.clone().finalize()) != transfer.offer.sha256 {
+        server_state.transfers.remove(&transfer_id);
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "The file doesn't match its checksum, the upload was cancelled.",
+        ));
+    }
+
+    for recipient in &transfer.waiting {
+        if let Some(client) = server_state.clients.get(recipient) {
+            client.send(ServerToClient::FileOffered {
+                target: transfer.recipient_target.clone(),
+                offer: transfer.offer.clone(),
+            });
+        }
+    }
+    if let Some(client) = server_state.clients.get(&transfer.offer.sender) {
+        client.send(ServerToClient::FileOffered {
+            target: transfer.target.clone(),
+            offer: transfer.offer.clone(),
+        });
+    }
+
+    if transfer.waiting.is_empty() {
+        server_state.transfers.remove(&transfer_id);
+    }
+
     Ok(())
 }
+
+/// Send an offered file to `handle` in chunks. Each recipient can download it once.
+fn accept_file(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    transfer_id: u64,
+) -> Outcome {
+    let mut server_state = state.lock().unwrap();
+    server_state.expire_transfers();
+
+    let transfer = match server_state.transfers.get_mut(&transfer_id) {
+        Some(transfer) if transfer.uploaded() && transfer.waiting.contains(handle) => transfer,
+        _ => return Err(unknown_transfer()),
+    };
+
+    transfer.waiting.remove(handle);
+    let data = transfer.data.clone();
+    if transfer.waiting.is_empty() {
+        server_state.transfers.remove(&transfer_id);
+    }
+
+    // The chunks are queued from a task that leaves half of the client's queue free,
+    // so a large file neither overflows it nor holds up anything else for the client
+    let tx = client.tx.clone();
+    tokio::spawn(async move {
+        for (i, chunk) in data.chunks(FILE_CHUNK_SIZE).enumerate() {
+            while tx.capacity() < OUTBOUND_CAPACITY / 2 {
+                if tx.is_closed() {
+                    return;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let msg = ServerToClient::FileChunk {
+                transfer_id,
+                offset: (i * FILE_CHUNK_SIZE) as u64,
+                data: chunk.to_vec(),
+            };
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(ServerToClient::FileComplete { transfer_id }).await;
+    });
+
+    Ok(None)
+}
+
+/// Add `target` to or remove it from the block list of `handle`, and send the updated list.
+fn block(
+    client: &Client,
+    state: &Arc<Mutex<ServerState>>,
+    handle: &str,
+    target: String,
+    active: bool,
+) -> Outcome {
+    if target == handle {
+        return Err(RequestError::new(
+            ErrorCode::InvalidRequest,
+            "You can't block yourself.",
+        ));
+    }
+
+    let mut server_state = state.lock().unwrap();
+
+    if !server_state.known_users.contains(&target) {
+        return Err(RequestError::new(
+            ErrorCode::UnknownUser,
+            "Target handle doesn't exist.",
+        ));
+    }
+
+    server_state
+        .blocks
+        .set(handle, &target, active)
+        .map_err(store_error)?;
+
+    let handles = server_state.blocks.blocked(handle);
+    client.send(ServerToClient::BlockedList { handles });
+
+    Ok(None)
+}
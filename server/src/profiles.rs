@@ -0,0 +1,107 @@
+use crate::storage::JsonLog;
+use protocol::Presence;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+const PROFILES_FILE_NAME: &str = "profiles.log";
+
+/// Presence and status of a user.
+pub struct Profile {
+    pub presence: Presence,
+    pub status: Option<String>,
+    // When an offline user was last connected
+    pub last_seen: Option<u64>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            presence: Presence::Offline,
+            status: None,
+            last_seen: None,
+        }
+    }
+}
+
+/// The stored part of a profile, everyone is offline after a restart.
+#[derive(Serialize, Deserialize)]
+struct ProfileEntry {
+    handle: String,
+    status: Option<String>,
+    last_seen: Option<u64>,
+}
+
+/// The profiles of every user that has logged in. When opened with a data
+/// directory every change to a status or when a user was last seen is appended
+/// to a log in it.
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+    log: Option<JsonLog>,
+}
+
+impl Profiles {
+    pub fn open(data_dir: Option<&Path>) -> io::Result<Self> {
+        let mut profiles = Profiles {
+            profiles: HashMap::new(),
+            log: None,
+        };
+
+        let Some(data_dir) = data_dir else {
+            return Ok(profiles);
+        };
+
+        let log = JsonLog::open(data_dir, PROFILES_FILE_NAME, |entry: ProfileEntry| {
+            profiles.profiles.insert(
+                entry.handle,
+                Profile {
+                    presence: Presence::Offline,
+                    status: entry.status,
+                    last_seen: entry.last_seen,
+                },
+            );
+            Ok(())
+        })?;
+        profiles.log = Some(log);
+
+        Ok(profiles)
+    }
+
+    pub fn get(&self, handle: &str) -> Option<&Profile> {
+        self.profiles.get(handle)
+    }
+
+    /// Change the presence of `handle`. Only kept in memory.
+    pub fn set_presence(&mut self, handle: &str, presence: Presence) {
+        self.profiles
+            .entry(handle.to_string())
+            .or_default()
+            .presence = presence;
+    }
+
+    pub fn set_status(&mut self, handle: &str, status: Option<String>) -> io::Result<()> {
+        self.profiles.entry(handle.to_string()).or_default().status = status;
+        self.write(handle)
+    }
+
+    /// Mark `handle` as offline since `last_seen`.
+    pub fn set_offline(&mut self, handle: &str, last_seen: u64) -> io::Result<()> {
+        let profile = self.profiles.entry(handle.to_string()).or_default();
+        profile.presence = Presence::Offline;
+        profile.last_seen = Some(last_seen);
+        self.write(handle)
+    }
+
+    fn write(&mut self, handle: &str) -> io::Result<()> {
+        let (Some(log), Some(profile)) = (&mut self.log, self.profiles.get(handle)) else {
+            return Ok(());
+        };
+
+        log.append(&ProfileEntry {
+            handle: handle.to_string(),
+            status: profile.status.clone(),
+            last_seen: profile.last_seen,
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use crate::storage::JsonLog;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+
+const ROOMS_FILE_NAME: &str = "rooms.log";
+
+/// A user joining (`joined`) or leaving a room. The first join creates the room.
+#[derive(Serialize, Deserialize)]
+struct RoomEntry {
+    room: String,
+    member: String,
+    joined: bool,
+}
+
+/// Every room with the handles of its members. Rooms stay when their last member
+/// leaves. When opened with a data directory every change is appended to a log in it.
+pub struct Rooms {
+    members: HashMap<String, HashSet<String>>,
+    log: Option<JsonLog>,
+}
+
+impl Rooms {
+    pub fn open(data_dir: Option<&Path>) -> io::Result<Self> {
+        let mut rooms = Rooms {
+            members: HashMap::new(),
+            log: None,
+        };
+
+        let Some(data_dir) = data_dir else {
+            return Ok(rooms);
+        };
+
+        let log = JsonLog::open(data_dir, ROOMS_FILE_NAME, |entry| {
+            rooms.apply(entry);
+            Ok(())
+        })?;
+        rooms.log = Some(log);
+
+        Ok(rooms)
+    }
+
+    /// Add rooms known from elsewhere, e.g. the chat history, without members.
+    pub fn restore(&mut self, rooms: impl IntoIterator<Item = String>) {
+        for room in rooms {
+            self.members.entry(room).or_default();
+        }
+    }
+
+    pub fn exists(&self, room: &str) -> bool {
+        self.members.contains_key(room)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.members.keys()
+    }
+
+    /// The members of `room`, or `None` if it doesn't exist.
+    pub fn members(&self, room: &str) -> Option<&HashSet<String>> {
+        self.members.get(room)
+    }
+
+    /// Everyone who is in at least one room with `handle`, including `handle`.
+    pub fn co_members<'a>(&'a self, handle: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.members
+            .values()
+            .filter(move |members| members.contains(handle))
+            .flatten()
+    }
+
+    pub fn is_member(&self, room: &str, handle: &str) -> bool {
+        self.members
+            .get(room)
+            .is_some_and(|members| members.contains(handle))
+    }
+
+    /// Add `handle` to `room`, creating the room if it doesn't exist yet.
+    pub fn join(&mut self, room: &str, handle: &str) -> io::Result<()> {
+        if self.is_member(room, handle) {
+            return Ok(());
+        }
+        self.write(RoomEntry {
+            room: room.to_string(),
+            member: handle.to_string(),
+            joined: true,
+        })
+    }
+
+    /// Remove `handle` from `room`. Returns whether it was a member.
+    pub fn leave(&mut self, room: &str, handle: &str) -> io::Result<bool> {
+        if !self.is_member(room, handle) {
+            return Ok(false);
+        }
+        self.write(RoomEntry {
+            room: room.to_string(),
+            member: handle.to_string(),
+            joined: false,
+        })?;
+        Ok(true)
+    }
+
+    fn write(&mut self, entry: RoomEntry) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            log.append(&entry)?;
+        }
+
+        self.apply(entry);
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: RoomEntry) {
+        let members = self.members.entry(entry.room).or_default();
+        if entry.joined {
+            members.insert(entry.member);
+        } else {
+            members.remove(&entry.member);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::storage::ChatKey;
+use std::collections::{BTreeSet, HashMap};
+
+/// Inverted index from the words of every stored message to the IDs of the
+/// messages containing them. Kept in memory and rebuilt when the log is replayed.
+#[derive(Default)]
+pub struct SearchIndex {
+    words: HashMap<String, BTreeSet<u64>>,
+    // Conversation every indexed message belongs to
+    chats: HashMap<u64, ChatKey>,
+}
+
+/// Lowercase words of a text, split on everything that isn't a letter or digit.
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+impl SearchIndex {
+    pub fn insert(&mut self, key: &ChatKey, message_id: u64, content: &str) {
+        for word in words(content) {
+            self.words.entry(word).or_default().insert(message_id);
+        }
+        self.chats.insert(message_id, key.clone());
+    }
+
+    /// Forget a message, `content` has to be the text it was indexed with.
+    pub fn remove(&mut self, message_id: u64, content: &str) {
+        for word in words(content) {
+            if let Some(ids) = self.words.get_mut(&word) {
+                ids.remove(&message_id);
+                if ids.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+        self.chats.remove(&message_id);
+    }
+
+    /// Messages containing every word of `query`, newest first, with their conversation.
+    pub fn search<'a>(&'a self, query: &str) -> impl Iterator<Item = (&'a ChatKey, u64)> + 'a {
+        let mut postings: Vec<&BTreeSet<u64>> = Vec::new();
+        let mut unknown_word = false;
+        for word in words(query) {
+            match self.words.get(&word) {
+                Some(ids) => postings.push(ids),
+                None => unknown_word = true,
+            }
+        }
+        if unknown_word {
+            postings.clear();
+        }
+
+        // Walk the rarest word and check the others against it
+        postings.sort_by_key(|ids| ids.len());
+        let others = postings.split_off(postings.len().min(1));
+
+        postings
+            .into_iter()
+            .flat_map(|ids| ids.iter().rev())
+            .filter(move |id| others.iter().all(|ids| ids.contains(id)))
+            .filter_map(move |id| Some((self.chats.get(id)?, *id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(index: &SearchIndex, query: &str) -> Vec<u64> {
+        index.search(query).map(|(_, id)| id).collect()
+    }
+
+    fn index() -> SearchIndex {
+        let key = ChatKey::Direct("alice".to_string(), "bob".to_string());
+        let mut index = SearchIndex::default();
+        index.insert(&key, 1, "Lunch at noon?");
+        index.insert(&key, 2, "lunch is late today");
+        index.insert(&key, 3, "See you at noon");
+        index
+    }
+
+    #[test]
+    fn finds_messages_with_every_word_newest_first() {
+        let index = index();
+        assert_eq!(ids(&index, "LUNCH"), vec![2, 1]);
+        assert_eq!(ids(&index, "noon lunch"), vec![1]);
+        assert_eq!(ids(&index, "lunch dinner"), Vec::<u64>::new());
+        assert_eq!(ids(&index, "?!"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn edited_messages_are_found_by_their_new_words_only() {
+        let mut index = index();
+        let key = ChatKey::Direct("alice".to_string(), "bob".to_string());
+        // An edit is a removal of the old text followed by an insert of the new one
+        index.remove(1, "Lunch at noon?");
+        index.insert(&key, 1, "Dinner at eight?");
+
+        assert_eq!(ids(&index, "lunch"), vec![2]);
+        assert_eq!(ids(&index, "dinner"), vec![1]);
+        assert_eq!(ids(&index, "at"), vec![3, 1]);
+    }
+
+    #[test]
+    fn deleted_messages_are_forgotten() {
+        let mut index = index();
+        index.remove(2, "lunch is late today");
+        index.remove(3, "See you at noon");
+
+        assert_eq!(ids(&index, "lunch"), vec![1]);
+        assert_eq!(ids(&index, "noon"), vec![1]);
+        assert!(!index.words.contains_key("late"));
+        assert!(!index.chats.contains_key(&3));
+    }
+}
@@ -0,0 +1,877 @@
+use crate::search::SearchIndex;
+use protocol::{Message, Reaction};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Identifies a conversation. Direct chats use the sorted pair of participant
+/// handles, rooms their name (including the leading '#').
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ChatKey {
+    Direct(String, String),
+    Room(String),
+}
+
+const LOG_FILE_NAME: &str = "chats.log";
+
+/// Backend that chat history is written to and read from.
+pub trait ChatStore: Send {
+    /// Append a message to the conversation identified by `key`, assigning it
+    /// the next ID and the current time. Returns the stored message.
+    fn append(
+        &mut self,
+        key: &ChatKey,
+        sender: &str,
+        content: &str,
+        parent: Option<u64>,
+    ) -> io::Result<Message>;
+
+    /// Up to `limit` messages of the conversation with IDs between `after` and
+    /// `before` that pass `visible`, oldest first. Those closest to `after` if only
+    /// it is given, otherwise those closest to `before` or the newest. The flag
+    /// tells whether more visible messages were left out.
+    fn page(
+        &self,
+        key: &ChatKey,
+        after: Option<u64>,
+        before: Option<u64>,
+        limit: usize,
+        visible: &dyn Fn(&Message) -> bool,
+    ) -> (Vec<Message>, bool);
+
+    /// Every handle that takes part in at least one stored direct conversation.
+    fn participants(&self) -> HashSet<String>;
+
+    /// Everyone `handle` has a stored direct conversation with.
+    fn partners(&self, handle: &str) -> HashSet<String>;
+
+    /// The names of all rooms with stored messages.
+    fn rooms(&self) -> HashSet<String>;
+
+    /// Record that `reader` has seen every message up to `message_id` in the
+    /// conversation. Returns the new read position, or `None` if it didn't advance.
+    fn mark_read(
+        &mut self,
+        key: &ChatKey,
+        reader: &str,
+        message_id: u64,
+    ) -> io::Result<Option<u64>>;
+
+    /// ID of the last message `reader` has seen in the conversation.
+    fn read_position(&self, key: &ChatKey, reader: &str) -> Option<u64>;
+
+    /// The message with `message_id` in the conversation, if it exists.
+    fn message(&self, key: &ChatKey, message_id: u64) -> Option<Message>;
+
+    /// Replace the content of a message and mark it as edited now. Returns the
+    /// updated message, or `None` if it doesn't exist.
+    fn edit(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        content: &str,
+    ) -> io::Result<Option<Message>>;
+
+    /// Remove a message from the conversation. Returns whether it existed.
+    fn delete(&mut self, key: &ChatKey, message_id: u64) -> io::Result<bool>;
+
+    /// Add or take back the reaction of `user` with `emoji` to a message.
+    /// Returns the updated message, or `None` if it doesn't exist.
+    fn react(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        user: &str,
+        emoji: &str,
+        active: bool,
+    ) -> io::Result<Option<Message>>;
+
+    /// Up to `limit` messages containing every word of `query`, newest first,
+    /// from the conversations `include` accepts.
+    fn search(
+        &self,
+        query: &str,
+        include: &dyn Fn(&ChatKey, &Message) -> bool,
+        limit: usize,
+    ) -> Vec<(ChatKey, Message)>;
+}
+
+/// Keeps all conversations in memory. Everything is lost when the server exits.
+pub struct MemoryStore {
+    chats: HashMap<ChatKey, Vec<Message>>,
+    // Read position of every participant per conversation
+    read: HashMap<(ChatKey, String), u64>,
+    index: SearchIndex,
+    next_id: u64,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            chats: HashMap::new(),
+            read: HashMap::new(),
+            index: SearchIndex::default(),
+            next_id: 1,
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, the unit of every timestamp sent to clients.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// An append-only log with one JSON entry per line, kept in a data directory.
+pub struct JsonLog {
+    file: File,
+}
+
+impl JsonLog {
+    /// Open the log `name` in `data_dir`, first passing every entry already in it
+    /// to `apply`. Corrupt lines are skipped with a warning.
+    pub fn open<T>(
+        data_dir: &Path,
+        name: &str,
+        mut apply: impl FnMut(T) -> io::Result<()>,
+    ) -> io::Result<Self>
+    where
+        T: DeserializeOwned,
+    {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(name);
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                // A partially written last line is left behind if the server died mid-write
+                match serde_json::from_str::<T>(&line) {
+                    Ok(entry) => apply(entry)?,
+                    Err(e) => eprintln!(
+                        "Skipping corrupt entry on line {} of {}:\t{}",
+                        number + 1,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        // Without a newline after a torn last line the next entry would be glued
+        // onto it and lost with it on the next replay
+        if file.metadata()?.len() > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+                file.flush()?;
+            }
+        }
+
+        Ok(JsonLog { file })
+    }
+
+    pub fn append<T: Serialize>(&mut self, entry: &T) -> io::Result<()> {
+        let mut line =
+            serde_json::to_vec(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()
+    }
+}
+
+impl MemoryStore {
+    fn new_message(&self, sender: &str, content: &str, parent: Option<u64>) -> Message {
+        Message {
+            id: self.next_id,
+            timestamp: unix_time(),
+            sender: sender.to_string(),
+            content: content.to_string(),
+            edited: None,
+            reactions: Vec::new(),
+            parent,
+        }
+    }
+
+    /// Insert an already numbered message, numbering it first if it has no ID yet.
+    fn insert(&mut self, key: &ChatKey, mut message: Message) {
+        if message.id == 0 {
+            message.id = self.next_id;
+        }
+        self.next_id = self.next_id.max(message.id + 1);
+        self.index.insert(key, message.id, &message.content);
+        self.chats.entry(key.clone()).or_default().push(message);
+    }
+
+    /// The read position `mark_read` would move to, if it advances at all.
+    fn next_read_position(&self, key: &ChatKey, reader: &str, message_id: u64) -> Option<u64> {
+        // Nobody can have read past the newest message
+        let newest = self.chats.get(key)?.last()?.id;
+        let position = message_id.min(newest);
+
+        match self.read_position(key, reader) {
+            Some(current) if current >= position => None,
+            _ => Some(position),
+        }
+    }
+
+    /// Index of the message with `message_id` in its conversation. IDs only
+    /// ever increase, so every conversation is sorted by them.
+    fn position(&self, key: &ChatKey, message_id: u64) -> Option<usize> {
+        self.chats
+            .get(key)?
+            .binary_search_by_key(&message_id, |m| m.id)
+            .ok()
+    }
+
+    fn apply_edit(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        content: &str,
+        edited: u64,
+    ) -> Option<Message> {
+        let index = self.position(key, message_id)?;
+        let message = &mut self.chats.get_mut(key)?[index];
+        self.index.remove(message_id, &message.content);
+        self.index.insert(key, message_id, content);
+        message.content = content.to_string();
+        message.edited = Some(edited);
+        Some(message.clone())
+    }
+
+    fn apply_reaction(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        user: &str,
+        emoji: &str,
+        active: bool,
+    ) -> Option<Message> {
+        let index = self.position(key, message_id)?;
+        let message = &mut self.chats.get_mut(key)?[index];
+
+        match message.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            Some(reaction) if !active => reaction.users.retain(|u| u != user),
+            Some(reaction) if !reaction.users.iter().any(|u| u == user) => {
+                reaction.users.push(user.to_string())
+            }
+            Some(_) => {}
+            None if active => message.reactions.push(Reaction {
+                emoji: emoji.to_string(),
+                users: vec![user.to_string()],
+            }),
+            None => {}
+        }
+        message.reactions.retain(|r| !r.users.is_empty());
+
+        Some(message.clone())
+    }
+
+    fn apply_delete(&mut self, key: &ChatKey, message_id: u64) -> bool {
+        match self.position(key, message_id) {
+            Some(index) => {
+                let message = self.chats.get_mut(key).unwrap().remove(index);
+                self.index.remove(message_id, &message.content);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl ChatStore for MemoryStore {
+    fn append(
+        &mut self,
+        key: &ChatKey,
+        sender: &str,
+        content: &str,
+        parent: Option<u64>,
+    ) -> io::Result<Message> {
+        let message = self.new_message(sender, content, parent);
+        self.insert(key, message.clone());
+        Ok(message)
+    }
+
+    fn page(
+        &self,
+        key: &ChatKey,
+        after: Option<u64>,
+        before: Option<u64>,
+        limit: usize,
+        visible: &dyn Fn(&Message) -> bool,
+    ) -> (Vec<Message>, bool) {
+        let Some(chat) = self.chats.get(key) else {
+            return (Vec::new(), false);
+        };
+
+        let start = after.map_or(0, |after| chat.partition_point(|m| m.id <= after));
+        let end = before.map_or(chat.len(), |before| chat.partition_point(|m| m.id < before));
+        let range = &chat[start..end.max(start)];
+
+        // Skipped messages don't count towards the limit, so a page is only short
+        // when there is nothing more to show
+        if after.is_some() && before.is_none() {
+            let mut messages = range.iter().filter(|m| visible(m));
+            let page = messages.by_ref().take(limit).cloned().collect();
+            (page, messages.next().is_some())
+        } else {
+            let mut messages = range.iter().rev().filter(|m| visible(m));
+            let mut page: Vec<Message> = messages.by_ref().take(limit).cloned().collect();
+            page.reverse();
+            (page, messages.next().is_some())
+        }
+    }
+
+    fn participants(&self) -> HashSet<String> {
+        self.chats
+            .keys()
+            .flat_map(|key| match key {
+                ChatKey::Direct(a, b) => vec![a.clone(), b.clone()],
+                ChatKey::Room(_) => vec![],
+            })
+            .collect()
+    }
+
+    fn partners(&self, handle: &str) -> HashSet<String> {
+        self.chats
+            .keys()
+            .filter_map(|key| match key {
+                ChatKey::Direct(a, b) if a == handle => Some(b.clone()),
+                ChatKey::Direct(a, b) if b == handle => Some(a.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn rooms(&self) -> HashSet<String> {
+        self.chats
+            .keys()
+            .filter_map(|key| match key {
+                ChatKey::Room(name) => Some(name.clone()),
+                ChatKey::Direct(..) => None,
+            })
+            .collect()
+    }
+
+    fn mark_read(
+        &mut self,
+        key: &ChatKey,
+        reader: &str,
+        message_id: u64,
+    ) -> io::Result<Option<u64>> {
+        let position = self.next_read_position(key, reader, message_id);
+        if let Some(position) = position {
+            self.read
+                .insert((key.clone(), reader.to_string()), position);
+        }
+        Ok(position)
+    }
+
+    fn read_position(&self, key: &ChatKey, reader: &str) -> Option<u64> {
+        self.read.get(&(key.clone(), reader.to_string())).copied()
+    }
+
+    fn message(&self, key: &ChatKey, message_id: u64) -> Option<Message> {
+        let index = self.position(key, message_id)?;
+        Some(self.chats[key][index].clone())
+    }
+
+    fn edit(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        content: &str,
+    ) -> io::Result<Option<Message>> {
+        Ok(self.apply_edit(key, message_id, content, unix_time()))
+    }
+
+    fn delete(&mut self, key: &ChatKey, message_id: u64) -> io::Result<bool> {
+        Ok(self.apply_delete(key, message_id))
+    }
+
+    fn react(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        user: &str,
+        emoji: &str,
+        active: bool,
+    ) -> io::Result<Option<Message>> {
+        Ok(self.apply_reaction(key, message_id, user, emoji, active))
+    }
+
+    fn search(
+        &self,
+        query: &str,
+        include: &dyn Fn(&ChatKey, &Message) -> bool,
+        limit: usize,
+    ) -> Vec<(ChatKey, Message)> {
+        self.index
+            .search(query)
+            .filter_map(|(key, id)| Some((key.clone(), self.message(key, id)?)))
+            .filter(|(key, message)| include(key, message))
+            .take(limit)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum LogEntry {
+    Message {
+        key: ChatKey,
+        message: Message,
+    },
+    Read {
+        key: ChatKey,
+        reader: String,
+        message_id: u64,
+    },
+    Edit {
+        key: ChatKey,
+        message_id: u64,
+        content: String,
+        edited: u64,
+    },
+    Delete {
+        key: ChatKey,
+        message_id: u64,
+    },
+    React {
+        key: ChatKey,
+        message_id: u64,
+        user: String,
+        emoji: String,
+        active: bool,
+    },
+}
+
+/// An entry as read back from the log. Lines written before entries were tagged
+/// with their type only held messages.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEntry {
+    Tagged(LogEntry),
+    Untagged { key: ChatKey, message: Message },
+}
+
+/// Append-only log of every message, change to one and read position, one JSON entry per
+/// line. The log is replayed into memory when the store is opened.
+pub struct LogStore {
+    log: JsonLog,
+    chats: MemoryStore,
+}
+
+impl LogStore {
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        let mut chats = MemoryStore::default();
+
+        let log = JsonLog::open(data_dir, LOG_FILE_NAME, |entry| {
+            let entry = match entry {
+                StoredEntry::Tagged(entry) => entry,
+                StoredEntry::Untagged { key, message } => LogEntry::Message { key, message },
+            };
+            match entry {
+                LogEntry::Message { key, message } => chats.insert(&key, message),
+                LogEntry::Read {
+                    key,
+                    reader,
+                    message_id,
+                } => {
+                    chats.mark_read(&key, &reader, message_id)?;
+                }
+                LogEntry::Edit {
+                    key,
+                    message_id,
+                    content,
+                    edited,
+                } => {
+                    chats.apply_edit(&key, message_id, &content, edited);
+                }
+                LogEntry::Delete { key, message_id } => {
+                    chats.apply_delete(&key, message_id);
+                }
+                LogEntry::React {
+                    key,
+                    message_id,
+                    user,
+                    emoji,
+                    active,
+                } => {
+                    chats.apply_reaction(&key, message_id, &user, &emoji, active);
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(LogStore { log, chats })
+    }
+}
+
+impl ChatStore for LogStore {
+    fn append(
+        &mut self,
+        key: &ChatKey,
+        sender: &str,
+        content: &str,
+        parent: Option<u64>,
+    ) -> io::Result<Message> {
+        let message = self.chats.new_message(sender, content, parent);
+        self.log.append(&LogEntry::Message {
+            key: key.clone(),
+            message: message.clone(),
+        })?;
+
+        self.chats.insert(key, message.clone());
+        Ok(message)
+    }
+
+    fn page(
+        &self,
+        key: &ChatKey,
+        after: Option<u64>,
+        before: Option<u64>,
+        limit: usize,
+        visible: &dyn Fn(&Message) -> bool,
+    ) -> (Vec<Message>, bool) {
+        self.chats.page(key, after, before, limit, visible)
+    }
+
+    fn participants(&self) -> HashSet<String> {
+        self.chats.participants()
+    }
+
+    fn partners(&self, handle: &str) -> HashSet<String> {
+        self.chats.partners(handle)
+    }
+
+    fn rooms(&self) -> HashSet<String> {
+        self.chats.rooms()
+    }
+
+    fn mark_read(
+        &mut self,
+        key: &ChatKey,
+        reader: &str,
+        message_id: u64,
+    ) -> io::Result<Option<u64>> {
+        let Some(position) = self.chats.next_read_position(key, reader, message_id) else {
+            return Ok(None);
+        };
+
+        self.log.append(&LogEntry::Read {
+            key: key.clone(),
+            reader: reader.to_string(),
+            message_id: position,
+        })?;
+
+        self.chats.mark_read(key, reader, position)
+    }
+
+    fn read_position(&self, key: &ChatKey, reader: &str) -> Option<u64> {
+        self.chats.read_position(key, reader)
+    }
+
+    fn message(&self, key: &ChatKey, message_id: u64) -> Option<Message> {
+        self.chats.message(key, message_id)
+    }
+
+    fn edit(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        content: &str,
+    ) -> io::Result<Option<Message>> {
+        if self.chats.position(key, message_id).is_none() {
+            return Ok(None);
+        }
+
+        let edited = unix_time();
+        self.log.append(&LogEntry::Edit {
+            key: key.clone(),
+            message_id,
+            content: content.to_string(),
+            edited,
+        })?;
+
+        Ok(self.chats.apply_edit(key, message_id, content, edited))
+    }
+
+    fn delete(&mut self, key: &ChatKey, message_id: u64) -> io::Result<bool> {
+        if self.chats.position(key, message_id).is_none() {
+            return Ok(false);
+        }
+
+        self.log.append(&LogEntry::Delete {
+            key: key.clone(),
+            message_id,
+        })?;
+
+        Ok(self.chats.apply_delete(key, message_id))
+    }
+    fn react(
+        &mut self,
+        key: &ChatKey,
+        message_id: u64,
+        user: &str,
+        emoji: &str,
+        active: bool,
+    ) -> io::Result<Option<Message>> {
+        if self.chats.position(key, message_id).is_none() {
+            return Ok(None);
+        }
+
+        self.log.append(&LogEntry::React {
+            key: key.clone(),
+            message_id,
+            user: user.to_string(),
+            emoji: emoji.to_string(),
+            active,
+        })?;
+
+        Ok(self
+            .chats
+            .apply_reaction(key, message_id, user, emoji, active))
+    }
+    fn search(
+        &self,
+        query: &str,
+        include: &dyn Fn(&ChatKey, &Message) -> bool,
+        limit: usize,
+    ) -> Vec<(ChatKey, Message)> {
+        self.chats.search(query, include, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn direct() -> ChatKey {
+        ChatKey::Direct("alice".to_string(), "bob".to_string())
+    }
+
+    /// A store with messages 1 to `count` from alice to bob.
+    fn store_with(count: u64) -> MemoryStore {
+        let mut store = MemoryStore::default();
+        for i in 1..=count {
+            store
+                .append(&direct(), "alice", &i.to_string(), None)
+                .unwrap();
+        }
+        store
+    }
+
+    fn page_ids(
+        store: &impl ChatStore,
+        after: Option<u64>,
+        before: Option<u64>,
+        limit: usize,
+    ) -> (Vec<u64>, bool) {
+        let (page, has_more) = store.page(&direct(), after, before, limit, &|_| true);
+        (page.iter().map(|m| m.id).collect(), has_more)
+    }
+
+    /// A data directory that is removed again when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "chat-rs-storage-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn page_without_cursors_returns_the_newest() {
+        let store = store_with(5);
+        assert_eq!(page_ids(&store, None, None, 2), (vec![4, 5], true));
+        assert_eq!(
+            page_ids(&store, None, None, 5),
+            (vec![1, 2, 3, 4, 5], false)
+        );
+    }
+
+    #[test]
+    fn page_after_only_returns_the_oldest_following() {
+        let store = store_with(5);
+        assert_eq!(page_ids(&store, Some(1), None, 2), (vec![2, 3], true));
+        assert_eq!(page_ids(&store, Some(3), None, 2), (vec![4, 5], false));
+        assert_eq!(page_ids(&store, Some(5), None, 2), (vec![], false));
+    }
+
+    #[test]
+    fn page_before_returns_the_newest_preceding() {
+        let store = store_with(5);
+        assert_eq!(page_ids(&store, None, Some(5), 2), (vec![3, 4], true));
+        assert_eq!(page_ids(&store, None, Some(3), 2), (vec![1, 2], false));
+        assert_eq!(page_ids(&store, None, Some(1), 2), (vec![], false));
+    }
+
+    #[test]
+    fn page_between_cursors_returns_the_newest_in_between() {
+        let store = store_with(5);
+        assert_eq!(page_ids(&store, Some(1), Some(5), 2), (vec![3, 4], true));
+        assert_eq!(
+            page_ids(&store, Some(1), Some(5), 3),
+            (vec![2, 3, 4], false)
+        );
+        // Crossed cursors select nothing
+        assert_eq!(page_ids(&store, Some(4), Some(2), 2), (vec![], false));
+    }
+
+    #[test]
+    fn page_skips_hidden_messages_without_counting_them() {
+        let store = store_with(6);
+        let odd = |m: &Message| m.id % 2 == 1;
+
+        let (page, has_more) = store.page(&direct(), None, None, 2, &odd);
+        assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), vec![3, 5]);
+        assert!(has_more);
+
+        let (page, has_more) = store.page(&direct(), Some(2), None, 2, &odd);
+        assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), vec![3, 5]);
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn page_of_an_unknown_chat_is_empty() {
+        let store = store_with(3);
+        let key = ChatKey::Room("#nowhere".to_string());
+        let (page, has_more) = store.page(&key, None, None, 10, &|_| true);
+        assert!(page.is_empty());
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn search_follows_edits_and_deletes() {
+        let mut store = store_with(0);
+        let key = direct();
+        store.append(&key, "alice", "lunch at noon", None).unwrap();
+        store.append(&key, "bob", "lunch is late", None).unwrap();
+        let search = |store: &MemoryStore, query| {
+            let hits = store.search(query, &|_, _| true, 10);
+            hits.into_iter().map(|(_, m)| m.id).collect::<Vec<_>>()
+        };
+
+        store.edit(&key, 1, "dinner at eight").unwrap().unwrap();
+        assert_eq!(search(&store, "lunch"), vec![2]);
+        assert_eq!(search(&store, "dinner"), vec![1]);
+
+        assert!(store.delete(&key, 2).unwrap());
+        assert!(search(&store, "lunch").is_empty());
+        assert!(search(&store, "late").is_empty());
+    }
+
+    #[test]
+    fn log_store_replays_every_change() {
+        let dir = TestDir::new("replay");
+        let key = direct();
+
+        {
+            let mut store = LogStore::open(&dir.0).unwrap();
+            for content in ["one", "two", "three"] {
+                store.append(&key, "alice", content, None).unwrap();
+            }
+            store.edit(&key, 1, "one, edited").unwrap().unwrap();
+            assert!(store.delete(&key, 2).unwrap());
+            store.react(&key, 3, "bob", "👍", true).unwrap().unwrap();
+            store.react(&key, 3, "carol", "👍", true).unwrap().unwrap();
+            store.react(&key, 3, "carol", "👍", false).unwrap().unwrap();
+            assert_eq!(store.mark_read(&key, "bob", 3).unwrap(), Some(3));
+        }
+
+        let store = LogStore::open(&dir.0).unwrap();
+        let (page, has_more) = store.page(&key, None, None, 10, &|_| true);
+        assert!(!has_more);
+        assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(page[0].content, "one, edited");
+        assert!(page[0].edited.is_some());
+        assert_eq!(
+            page[1].reactions,
+            vec![Reaction {
+                emoji: "👍".to_string(),
+                users: vec!["bob".to_string()],
+            }]
+        );
+        assert_eq!(store.read_position(&key, "bob"), Some(3));
+        assert_eq!(store.read_position(&key, "alice"), None);
+    }
+
+    #[test]
+    fn log_store_numbers_messages_without_ids() {
+        let dir = TestDir::new("renumber");
+        fs::create_dir_all(&dir.0).unwrap();
+        // History from before IDs and tagged entries, followed by a current entry
+        let room = ChatKey::Room("#general".to_string());
+        let lines = [
+            r#"{"key":["alice","bob"],"message":{"sender":"alice","content":"first"}}"#,
+            r##"{"key":"#general","message":{"sender":"bob","content":"second"}}"##,
+            r#"{"key":["alice","bob"],"message":{"sender":"bob","content":"third"}}"#,
+            r#"{"type":"Message","key":["alice","bob"],"message":{"id":7,"sender":"alice","content":"fourth"}}"#,
+        ];
+        fs::write(dir.0.join(LOG_FILE_NAME), lines.join("\n")).unwrap();
+
+        let mut store = LogStore::open(&dir.0).unwrap();
+        assert_eq!(page_ids(&store, None, None, 10), (vec![1, 3, 7], false));
+        assert_eq!(store.message(&room, 2).unwrap().content, "second");
+
+        // New messages continue after the highest ID
+        let message = store.append(&direct(), "alice", "fifth", None).unwrap();
+        assert_eq!(message.id, 8);
+    }
+
+    #[test]
+    fn log_store_skips_a_torn_last_line() {
+        let dir = TestDir::new("torn");
+        {
+            let mut store = LogStore::open(&dir.0).unwrap();
+            store.append(&direct(), "alice", "kept", None).unwrap();
+        }
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(LOG_FILE_NAME))
+            .unwrap();
+        log.write_all(br#"{"type":"Message","key":["al"#).unwrap();
+
+        {
+            let mut store = LogStore::open(&dir.0).unwrap();
+            store.append(&direct(), "bob", "after", None).unwrap();
+        }
+
+        let store = LogStore::open(&dir.0).unwrap();
+        assert_eq!(page_ids(&store, None, None, 10), (vec![1, 2], false));
+    }
+}
@@ -0,0 +1,152 @@
+//! TLS over loopback between the server's acceptor and the client's `Connection`, with
+//! certificates generated for each test.
+
+use protocol::codec::{read_frame, write_frame};
+use protocol::transport::{self, Connection, ServerTrust};
+use protocol::{recv_msg, send_msg, Codec, DEFAULT_MAX_FRAME_SIZE};
+use std::fs;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio_rustls::TlsAcceptor;
+
+/// A self-signed certificate for "localhost" and its key, written to PEM files in a
+/// directory that is removed again when dropped.
+struct TestCert {
+    dir: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TestCert {
+    fn generate(name: &str) -> Self {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("chat-rs-tls-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        fs::write(&cert, certified.cert.pem()).unwrap();
+        fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        TestCert { dir, cert, key }
+    }
+}
+
+impl Drop for TestCert {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Accept a single TLS connection the way the server does, echo one frame back and wait
+/// for the client to close.
+fn echo_server(cert: &TestCert) -> (u16, thread::JoinHandle<io::Result<()>>) {
+    let acceptor = TlsAcceptor::from(transport::server_config(&cert.cert, &cert.key).unwrap());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let runtime = runtime::Builder::new_current_thread().enable_io().build()?;
+        runtime.block_on(async move {
+            let listener = TcpListener::from_std(listener)?;
+            let (socket, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(socket).await?;
+            if let Some(data) = read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).await? {
+                let msg: String = Codec::Json.decode(&data)?;
+                write_frame(&mut stream, &Codec::Json.encode(&msg)?).await?;
+            }
+            // The client shutting down its side ends the connection cleanly
+            assert_eq!(read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).await?, None);
+            Ok(())
+        })
+    });
+
+    (port, server)
+}
+
+fn connect(port: u16, trust: ServerTrust) -> io::Result<Connection> {
+    let socket = TcpStream::connect(("127.0.0.1", port))?;
+    Connection::connect_tls(socket, transport::client_config(trust), "localhost")
+}
+
+#[test]
+fn pinned_certificate_is_accepted() {
+    let cert = TestCert::generate("pinned");
+    let (port, server) = echo_server(&cert);
+
+    let mut conn = connect(port, ServerTrust::pinned(&cert.cert).unwrap()).unwrap();
+    assert_eq!(conn.peer_addr().unwrap().port(), port);
+
+    send_msg(&mut conn, Codec::Json, &"hello").unwrap();
+    let reply = recv_msg(&mut conn).unwrap().unwrap();
+    assert_eq!(Codec::Json.decode::<String>(&reply).unwrap(), "hello");
+
+    conn.shutdown(Shutdown::Write).unwrap();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn insecure_trust_accepts_any_certificate() {
+    let cert = TestCert::generate("insecure");
+    let (port, server) = echo_server(&cert);
+
+    let mut conn = connect(port, ServerTrust::Insecure).unwrap();
+    send_msg(&mut conn, Codec::Json, &"hi").unwrap();
+    assert!(recv_msg(&mut conn).unwrap().is_some());
+
+    conn.shutdown(Shutdown::Write).unwrap();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn clones_share_the_session() {
+    let cert = TestCert::generate("clone");
+    let (port, server) = echo_server(&cert);
+
+    let conn = connect(port, ServerTrust::Insecure).unwrap();
+    let mut writer = conn.try_clone().unwrap();
+    let mut reader = conn;
+
+    send_msg(&mut writer, Codec::Json, &"from a clone").unwrap();
+    assert!(recv_msg(&mut reader).unwrap().is_some());
+
+    writer.shutdown(Shutdown::Write).unwrap();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn pin_mismatch_is_rejected() {
+    let cert = TestCert::generate("mismatch-server");
+    let other = TestCert::generate("mismatch-pinned");
+    let (port, server) = echo_server(&cert);
+
+    assert!(connect(port, ServerTrust::pinned(&other.cert).unwrap()).is_err());
+    // The server sees the handshake fail as well
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn self_signed_certificate_fails_webpki_verification() {
+    let cert = TestCert::generate("webpki");
+    let (port, server) = echo_server(&cert);
+
+    assert!(connect(port, ServerTrust::WebPki).is_err());
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn server_config_rejects_missing_and_mismatched_files() {
+    let cert = TestCert::generate("config");
+    let other = TestCert::generate("config-other");
+
+    let missing = cert.cert.with_file_name("missing.pem");
+    assert!(transport::server_config(&missing, &cert.key).is_err());
+    assert!(transport::server_config(&cert.cert, &other.key).is_err());
+    assert!(transport::server_config(&cert.key, &cert.cert).is_err());
+    assert!(ServerTrust::pinned(&missing).is_err());
+}